@@ -0,0 +1,394 @@
+use std::{
+    collections::HashMap,
+    fs,
+    sync::{LazyLock, Mutex},
+    time::SystemTime,
+};
+
+use crate::{
+    comic::Comic,
+    compare_ids,
+    structs::{Config, Eviction, GggCacheData},
+    Error,
+};
+
+/// What's in a cache directory.
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    /// Pages whose image is on disk.
+    pub pages: usize,
+    /// Pages the index knows about, cached or not.
+    pub indexed: usize,
+    /// The size of every cached image, in bytes.
+    pub bytes: u64,
+    /// The earliest cached page's id.
+    pub oldest: Option<String>,
+    /// The latest cached page's id.
+    pub newest: Option<String>,
+}
+
+pub fn stats(cache_dir: &String) -> Result<CacheStats, Error> {
+    let index = GggCacheData::read(cache_dir.clone());
+    let cached = cached_images(cache_dir, &index)?;
+
+    Ok(CacheStats {
+        pages: cached.len(),
+        indexed: index.len(),
+        bytes: cached.iter().map(|c| c.bytes).sum(),
+        oldest: cached
+            .iter()
+            .map(|c| &c.entry.id)
+            .min_by(|a, b| compare_ids(a, b))
+            .cloned(),
+        newest: cached
+            .iter()
+            .map(|c| &c.entry.id)
+            .max_by(|a, b| compare_ids(a, b))
+            .cloned(),
+    })
+}
+
+/// What a prune deleted.
+#[derive(Debug, Default)]
+pub struct PruneReport {
+    pub removed: usize,
+    pub freed_bytes: u64,
+    /// The size of every image that's still cached.
+    pub kept_bytes: u64,
+}
+
+/// The limits a prune enforces. Usually straight from the `Config`.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_bytes: Option<u64>,
+    pub keep_within: Option<usize>,
+    pub eviction: Eviction,
+}
+
+impl Limits {
+    pub fn from_config(config: &Config) -> Limits {
+        Limits {
+            max_bytes: config.max_cache_bytes,
+            keep_within: config.keep_within_pages_of_bookmark,
+            eviction: config.eviction,
+        }
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.max_bytes.is_none() && self.keep_within.is_none()
+    }
+}
+
+/// Deletes cached images until the cache fits `limits`.
+///
+/// First, everything further than `keep_within` pages from the reading position, every bookmark
+/// and the pages in `keep` goes. Then, if it's still over `max_bytes`, images are deleted in
+/// `eviction` order. The pages the reading position, bookmarks and `keep` are on are never
+/// deleted, and neither are any index entries, so navigating never loses its links; deleted
+/// images are just downloaded again when they're needed.
+pub fn prune(
+    comic: &dyn Comic,
+    config: &Config,
+    limits: Limits,
+    cache_dir: &String,
+    keep: &[&str],
+) -> Result<PruneReport, Error> {
+    let mut report = PruneReport::default();
+    if limits.is_unlimited() {
+        return Ok(report);
+    }
+
+    let index = GggCacheData::read(cache_dir.clone());
+    // distances are counted in pages the index knows about, which is every page once it's synced
+    let mut order = index.iter().map(|p| &p.id[..]).collect::<Vec<&str>>();
+    order.sort_by(|a, b| compare_ids(a, b));
+    let rank = |id: &str| order.partition_point(|o| compare_ids(o, id).is_lt());
+
+    let mut protected = config
+        .bookmarks_for(comic)
+        .into_iter()
+        .map(|b| rank(&b.page))
+        .collect::<Vec<usize>>();
+    protected.push(rank(&config.reading_position(comic)));
+    protected.extend(keep.iter().map(|k| rank(k)));
+    let distance = |id: &str| {
+        let at = rank(id);
+        protected.iter().map(|p| p.abs_diff(at)).min().unwrap_or(0)
+    };
+
+    let mut cached = cached_images(cache_dir, &index)?;
+    let mut evict = |image: &CachedImage| -> Result<(), Error> {
+        let path = image.entry.path(cache_dir);
+        fs::remove_file(&path).map_err(|why| Error::io(path, why))?;
+        report.removed += 1;
+        report.freed_bytes += image.bytes;
+        Ok(())
+    };
+
+    if let Some(keep_within) = limits.keep_within {
+        let (far, near): (Vec<CachedImage>, Vec<CachedImage>) = cached
+            .into_iter()
+            .partition(|c| distance(&c.entry.id) > keep_within);
+        for image in &far {
+            evict(image)?;
+        }
+        cached = near;
+    }
+
+    let mut total: u64 = cached.iter().map(|c| c.bytes).sum();
+    if let Some(max_bytes) = limits.max_bytes {
+        match limits.eviction {
+            Eviction::Lru => cached.sort_by_key(|c| c.used),
+            Eviction::Distance => cached.sort_by_key(|c| std::cmp::Reverse(distance(&c.entry.id))),
+        }
+
+        for image in cached.iter().filter(|c| distance(&c.entry.id) > 0) {
+            if total <= max_bytes {
+                break;
+            }
+            evict(image)?;
+            total -= image.bytes;
+        }
+    }
+    report.kept_bytes = total;
+
+    Ok(report)
+}
+
+/// How many saves `prune_after_save` lets through before pruning anyway, for `keep_within`.
+const PRUNE_EVERY: usize = 50;
+
+/// What `prune_after_save` knows about each cache directory, so it doesn't have to look at every
+/// cached image after every save.
+static USAGE: LazyLock<Mutex<HashMap<String, Usage>>> = LazyLock::new(Default::default);
+
+#[derive(Debug)]
+struct Usage {
+    /// The size of every cached image, as of the last prune plus what's been saved since.
+    bytes: u64,
+    /// Saves since the last prune.
+    saves: usize,
+}
+
+/// Prunes using `config`'s limits after `saved` were downloaded, without touching them. It only
+/// actually prunes once the cache has grown past `max_cache_bytes`, or every `PRUNE_EVERY` saves.
+/// Failures are only logged.
+pub fn prune_after_save(
+    comic: &dyn Comic,
+    config: &Config,
+    cache_dir: &String,
+    saved: &[&GggCacheData],
+) {
+    let limits = Limits::from_config(config);
+    if limits.is_unlimited() {
+        return;
+    }
+
+    let mut usage = USAGE.lock().unwrap_or_else(|e| e.into_inner());
+    let usage = match usage.get_mut(cache_dir) {
+        Some(usage) => {
+            usage.saves += saved.len();
+            usage.bytes += saved
+                .iter()
+                .filter_map(|s| fs::metadata(s.path(cache_dir)).ok())
+                .map(|m| m.len())
+                .sum::<u64>();
+            usage
+        }
+        // the first save in this cache directory, so there's no way around looking at everything
+        None => {
+            let index = GggCacheData::read(cache_dir.clone());
+            let bytes = match cached_images(cache_dir, &index) {
+                Ok(cached) => cached.iter().map(|c| c.bytes).sum(),
+                Err(why) => {
+                    eprintln!("Couldn't prune the cache: {}", why);
+                    return;
+                }
+            };
+            usage
+                .entry(cache_dir.clone())
+                .or_insert(Usage { bytes, saves: 0 })
+        }
+    };
+
+    let over = limits.max_bytes.is_some_and(|max| usage.bytes > max);
+    if !over && usage.saves < PRUNE_EVERY {
+        return;
+    }
+
+    let keep = saved.iter().map(|s| &s.id[..]).collect::<Vec<&str>>();
+    match prune(comic, config, limits, cache_dir, &keep) {
+        Ok(report) => {
+            usage.bytes = report.kept_bytes;
+            usage.saves = 0;
+        }
+        Err(why) => eprintln!("Couldn't prune the cache: {}", why),
+    }
+}
+
+#[derive(Debug)]
+struct CachedImage {
+    entry: GggCacheData,
+    bytes: u64,
+    /// When the image was last looked at (see `GggCacheData::touch`).
+    used: SystemTime,
+}
+
+/// Every index entry whose image is on disk, with its size and when it was last used.
+fn cached_images(cache_dir: &String, index: &[GggCacheData]) -> Result<Vec<CachedImage>, Error> {
+    let mut cached = vec![];
+
+    for entry in index {
+        let path = entry.path(cache_dir);
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(why) if why.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(why) => return Err(Error::io(path, why)),
+        };
+
+        cached.push(CachedImage {
+            entry: entry.clone(),
+            bytes: metadata.len(),
+            used: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+        });
+    }
+
+    Ok(cached)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{comic::PageMetadata, girl_genius::GirlGenius, structs::Bookmark};
+
+    /// A cache directory holding a 100-byte image for each id in `used`, which lists them from
+    /// least to most recently used.
+    fn cache_dir(name: &str, used: &[&str]) -> String {
+        let dir = std::env::temp_dir()
+            .join(format!("ggg-prune-{}-{}", name, std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut index = vec![];
+        for (minutes, id) in used.iter().enumerate() {
+            let entry = GggCacheData {
+                id: id.to_string(),
+                filename: format!("{}.png", id),
+                previous: None,
+                next: None,
+                metadata: PageMetadata::default(),
+            };
+            let file = fs::File::create(entry.path(&dir)).unwrap();
+            file.set_len(100).unwrap();
+            file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(60 * minutes as u64))
+                .unwrap();
+            index.push(entry);
+        }
+        GggCacheData::write(&dir, &index).unwrap();
+
+        dir
+    }
+
+    /// The pages whose image is still there, in id order.
+    fn left(dir: &String) -> Vec<String> {
+        let mut left = GggCacheData::read(dir.clone())
+            .into_iter()
+            .filter(|p| p.is_cached(dir))
+            .map(|p| p.id)
+            .collect::<Vec<String>>();
+        left.sort_by(|a, b| compare_ids(a, b));
+        left
+    }
+
+    fn reading_at(page: &str) -> Config {
+        Config {
+            latest_page: page.to_string(),
+            ..Config::default()
+        }
+    }
+
+    fn limits(max_bytes: u64, eviction: Eviction) -> Limits {
+        Limits {
+            max_bytes: Some(max_bytes),
+            keep_within: None,
+            eviction,
+        }
+    }
+
+    #[test]
+    fn lru_evicts_the_least_recently_used_images() {
+        let dir = cache_dir("lru", &["4", "2", "6", "3", "5", "1"]);
+
+        let report = prune(
+            &GirlGenius,
+            &reading_at("1"),
+            limits(300, Eviction::Lru),
+            &dir,
+            &[],
+        );
+        let left = left(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let report = report.unwrap();
+        assert_eq!(left, ["1", "3", "5"]);
+        assert_eq!((report.removed, report.freed_bytes), (3, 300));
+        assert_eq!(report.kept_bytes, 300);
+    }
+
+    #[test]
+    fn distance_evicts_the_images_furthest_from_the_reader() {
+        let dir = cache_dir("distance", &["4", "2", "6", "3", "5", "1"]);
+
+        let report = prune(
+            &GirlGenius,
+            &reading_at("1"),
+            limits(300, Eviction::Distance),
+            &dir,
+            &[],
+        );
+        let left = left(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(report.unwrap().removed, 3);
+        assert_eq!(left, ["1", "2", "3"]);
+    }
+
+    #[test]
+    fn the_reader_bookmarks_and_just_saved_pages_are_never_evicted() {
+        let dir = cache_dir("guard", &["1", "2", "3", "4", "5", "6"]);
+        let mut config = reading_at("1");
+        config.bookmarks.push(Bookmark {
+            name: "old arc".to_string(),
+            comic: GirlGenius.name().to_string(),
+            page: "3".to_string(),
+        });
+
+        for eviction in [Eviction::Lru, Eviction::Distance] {
+            prune(&GirlGenius, &config, limits(0, eviction), &dir, &["6"]).unwrap();
+        }
+        let left = left(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(left, ["1", "3", "6"]);
+    }
+
+    #[test]
+    fn pages_too_far_from_the_reader_are_evicted_whatever_the_size() {
+        let dir = cache_dir("keep-within", &["1", "2", "3", "4", "5", "6"]);
+        let limits = Limits {
+            max_bytes: None,
+            keep_within: Some(2),
+            eviction: Eviction::Lru,
+        };
+
+        let report = prune(&GirlGenius, &reading_at("2"), limits, &dir, &[]);
+        let left = left(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(report.unwrap().removed, 2);
+        assert_eq!(left, ["1", "2", "3", "4"]);
+    }
+}
@@ -0,0 +1,233 @@
+use std::{fmt::Debug, path::Path, sync::Arc};
+
+use chrono::NaiveDate;
+use regex::Regex;
+use scraper::Html;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    definition::{is_safe_name, ComicDefinition},
+    girl_genius::GirlGenius,
+    Error,
+};
+
+/// A webcomic we know how to read: where its pages are, and how to pull the comic out of them.
+///
+/// Everything here is synchronous; fetching pages is `ComicPage`'s job, so the same fetching,
+/// caching and navigation code works for every comic.
+pub trait Comic: Send + Sync + Debug {
+    /// A short, filesystem-safe name, like `girl-genius`.
+    fn name(&self) -> &str;
+
+    /// What to call the comic in the UI.
+    fn title(&self) -> &str;
+
+    /// The id of the very first page.
+    fn first_page(&self) -> &str;
+
+    fn page_url(&self, id: &str) -> String;
+
+    /// A URL that always shows the newest page (usually the front page).
+    fn latest_url(&self) -> String;
+
+    /// Pulls a page id back out of one of the comic's URLs.
+    fn page_id(&self, url: &str) -> Option<String>;
+
+    /// Turns whatever the user typed into a page id.
+    fn parse_id(&self, input: &str) -> Result<String, Error>;
+
+    /// The id just before `id`, for guessing our way to a real page when `id` doesn't have one.
+    fn step_back(&self, id: &str) -> Option<String> {
+        let number = id.parse::<u64>().ok()?;
+        number.checked_sub(1).map(|n| n.to_string())
+    }
+
+    /// How many times `step_back` is tried before deciding there's no page near an id: the
+    /// longest the comic goes between pages. A month of dates (or 31 numbers) by default.
+    fn max_gap(&self) -> usize {
+        31
+    }
+
+    /// Pulls the images, links and metadata out of a page. `url` is where the page really is,
+    /// after any redirects.
+    fn scrape(&self, url: &str, html: &Html) -> Result<Scraped, Error>;
+}
+
+/// Everything `Comic::scrape` found on a page. Links are full URLs.
+#[derive(Debug, Clone, Default)]
+pub struct Scraped {
+    /// The comic's image(s), in reading order. There's always at least one.
+    pub images: Vec<String>,
+    pub previous_url: Option<String>,
+    pub next_url: Option<String>,
+    pub first_url: Option<String>,
+    pub last_url: Option<String>,
+    pub metadata: PageMetadata,
+}
+
+/// What a page says about itself, besides its image and links. Sites don't always say, so
+/// everything's optional; it's kept in the cache index, so it only has to be scraped once.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PageMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volume: Option<u32>,
+    /// The storyline/chapter the page belongs to, like `Volume 9` or `The Second Voyage of
+    /// the Beetleburg Clank`. Pages with the same chapter are exported and listed together.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chapter: Option<String>,
+    /// Where the page falls in its chapter, from 1. If the site doesn't say, it's counted from
+    /// the cache index when the page is recorded (see `GggCacheData::record`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page_in_chapter: Option<u32>,
+    /// The page's own title, if it has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// When the page went up, as `YYYY-MM-DD`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published: Option<String>,
+}
+
+impl PageMetadata {
+    /// Takes everything `newer` knows, keeping what we already had for anything it doesn't.
+    pub fn merge(&mut self, newer: &PageMetadata) {
+        fn take<T: Clone>(old: &mut Option<T>, new: &Option<T>) {
+            if new.is_some() {
+                *old = new.clone();
+            }
+        }
+        take(&mut self.volume, &newer.volume);
+        take(&mut self.chapter, &newer.chapter);
+        take(&mut self.page_in_chapter, &newer.page_in_chapter);
+        take(&mut self.title, &newer.title);
+        take(&mut self.published, &newer.published);
+    }
+
+    /// The first number in `text`, like the 9 in `Volume 9` or `Vol. 9: Agatha Awakens`.
+    pub fn number(text: &str) -> Option<u32> {
+        let regex = Regex::new(r"\d+").expect("Couldn't compile regex");
+        regex.find(text)?.as_str().parse().ok()
+    }
+
+    /// Reads a date the way sites tend to write them (`2009-03-18`, `20090318`,
+    /// `March 18, 2009`, `18 March 2009`, or the start of an ISO 8601 timestamp) as `YYYY-MM-DD`.
+    pub fn date(text: &str) -> Option<String> {
+        let text = text.trim();
+        let formats = [
+            "%Y-%m-%d",
+            "%Y%m%d",
+            "%B %d, %Y",
+            "%b %d, %Y",
+            "%d %B %Y",
+            "%d %b %Y",
+        ];
+        let iso = text.get(..10).unwrap_or(text);
+
+        formats
+            .iter()
+            .find_map(|format| {
+                NaiveDate::parse_from_str(text, format)
+                    .or_else(|_| NaiveDate::parse_from_str(iso, format))
+                    .ok()
+            })
+            .map(|date| date.format("%Y-%m-%d").to_string())
+    }
+}
+
+/// Finds a comic by name: either one that's built in, or one defined in `definitions_dir`.
+pub fn find_comic(name: &str, definitions_dir: &Path) -> Result<Arc<dyn Comic>, Error> {
+    if name == GirlGenius.name() {
+        return Ok(Arc::new(GirlGenius));
+    }
+
+    // a definition is usually named after its comic, and if that file is broken, that's the
+    // error to show rather than "there's no comic called that"
+    if is_safe_name(name) {
+        for extension in ["toml", "json"] {
+            let path = definitions_dir.join(format!("{}.{}", name, extension));
+            if path.is_file() {
+                let definition = ComicDefinition::read(&path)?;
+                if definition.name == name {
+                    return Ok(Arc::new(definition));
+                }
+            }
+        }
+    }
+
+    let definitions = ComicDefinition::read_dir(definitions_dir);
+    let known = definitions
+        .iter()
+        .map(|d| d.name.clone())
+        .collect::<Vec<String>>();
+
+    match definitions.into_iter().find(|d| d.name == name) {
+        Some(definition) => Ok(Arc::new(definition)),
+        None => Err(Error::Parse(format!(
+            "There's no comic called `{}`. Try `{}`{}, or add a definition to `{}`.",
+            name,
+            GirlGenius.name(),
+            known
+                .iter()
+                .map(|k| format!(", `{}`", k))
+                .collect::<String>(),
+            definitions_dir.display()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates_are_read_however_sites_write_them() {
+        for text in [
+            "2009-03-18",
+            "20090318",
+            "March 18, 2009",
+            "Mar 18, 2009",
+            "18 March 2009",
+            "18 Mar 2009",
+            "2009-03-18T09:30:00-05:00",
+            "  2009-03-18\n",
+        ] {
+            assert_eq!(
+                PageMetadata::date(text).as_deref(),
+                Some("2009-03-18"),
+                "{:?}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn things_that_arent_dates_arent() {
+        for text in ["", "Volume 9", "2009-02-30", "18/03/2009"] {
+            assert_eq!(PageMetadata::date(text), None, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn numbers_are_the_first_one_in_the_text() {
+        assert_eq!(PageMetadata::number("Volume 9"), Some(9));
+        assert_eq!(PageMetadata::number("Vol. 12: Agatha 3"), Some(12));
+        assert_eq!(PageMetadata::number("Prologue"), None);
+    }
+
+    #[test]
+    fn merging_keeps_what_the_newer_metadata_doesnt_know() {
+        let mut old = PageMetadata {
+            chapter: Some("Volume 1".to_string()),
+            page_in_chapter: Some(4),
+            ..PageMetadata::default()
+        };
+        old.merge(&PageMetadata {
+            chapter: Some("Volume 2".to_string()),
+            title: Some("Hello".to_string()),
+            ..PageMetadata::default()
+        });
+
+        assert_eq!(old.chapter.as_deref(), Some("Volume 2"));
+        assert_eq!(old.page_in_chapter, Some(4));
+        assert_eq!(old.title.as_deref(), Some("Hello"));
+    }
+}
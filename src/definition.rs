@@ -0,0 +1,409 @@
+use std::{fs, path::Path, sync::OnceLock};
+
+use chrono::{Duration, Utc};
+use regex::Regex;
+use scraper::{Html, Selector};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    comic::{Comic, PageMetadata, Scraped},
+    parse_date_id, Error,
+};
+
+/// A comic described by a TOML or JSON file, so new comics don't need a new build. For example:
+///
+/// ```toml
+/// name = "xkcd"
+/// url = "https://xkcd.com/{id}/"
+/// latest_url = "https://xkcd.com/"
+/// id_format = "number"
+/// first_page = "1"
+///
+/// [selectors]
+/// image = "#comic img"
+/// previous = "a[rel=prev]"
+/// next = "a[rel=next]"
+/// title = "#ctitle"
+/// ```
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ComicDefinition {
+    /// Lowercase letters, digits and `-` only, since it names the comic's cache directory.
+    pub name: String,
+    /// What to call the comic in the UI. Defaults to `name`.
+    #[serde(default)]
+    pub title: Option<String>,
+    /// The URL of a page, with `{id}` where the page id goes.
+    pub url: String,
+    /// A URL that always shows the newest page.
+    pub latest_url: String,
+    pub id_format: IdFormat,
+    pub first_page: String,
+    pub selectors: Selectors,
+    /// `id_regex`, compiled the first time a page id is needed.
+    #[serde(skip)]
+    id_regex: OnceLock<Regex>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdFormat {
+    /// `YYYYMMDD`, like Girl Genius.
+    Date,
+    /// `1`, `2`, `3`, ...
+    Number,
+}
+
+/// CSS selectors for the parts of a page we care about. Images are read from `src`, links from
+/// `href`, dates from `datetime` (or the text, if there isn't one), and everything else from
+/// the element's text.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Selectors {
+    pub image: String,
+    pub previous: String,
+    pub next: String,
+    #[serde(default)]
+    pub first: Option<String>,
+    #[serde(default)]
+    pub last: Option<String>,
+    /// The page's own title.
+    #[serde(default)]
+    pub title: Option<String>,
+    /// The storyline or chapter, which exports and the OPDS catalog group pages by.
+    #[serde(default)]
+    pub chapter: Option<String>,
+    /// Anything with the volume number in it, like `Volume 3`.
+    #[serde(default)]
+    pub volume: Option<String>,
+    /// Anything with the page's number within its chapter in it, like `Page 12`.
+    #[serde(default)]
+    pub page_number: Option<String>,
+    /// When the page went up. Not needed for comics whose ids are dates.
+    #[serde(default)]
+    pub date: Option<String>,
+}
+
+impl ComicDefinition {
+    pub fn read(path: &Path) -> Result<ComicDefinition, Error> {
+        let file =
+            fs::read_to_string(path).map_err(|why| Error::io(path.display().to_string(), why))?;
+
+        let parsed = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::from_str(&file).map_err(|why| why.to_string()),
+            _ => toml::from_str(&file).map_err(|why| why.to_string()),
+        };
+
+        let definition: ComicDefinition = parsed.map_err(|why| {
+            Error::Parse(format!(
+                "Couldn't parse the comic definition at `{}`: {}",
+                path.display(),
+                why
+            ))
+        })?;
+
+        // anything else could point the cache (and what `cache prune` deletes) outside it
+        if !is_safe_name(&definition.name) {
+            return Err(Error::Parse(format!(
+                "The comic definition at `{}` is called `{}`, but names can only have lowercase letters, digits and `-` in them",
+                path.display(),
+                definition.name
+            )));
+        }
+
+        Ok(definition)
+    }
+
+    /// Every definition in `dir` (`*.toml` and `*.json`). A missing directory just means there
+    /// aren't any, and a file that can't be read is skipped with a warning, so one broken
+    /// definition doesn't hide the rest.
+    pub fn read_dir(dir: &Path) -> Vec<ComicDefinition> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return vec![],
+        };
+
+        entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| {
+                matches!(
+                    p.extension().and_then(|e| e.to_str()),
+                    Some("toml" | "json")
+                )
+            })
+            .filter_map(|p| match ComicDefinition::read(&p) {
+                Ok(definition) => Some(definition),
+                Err(why) => {
+                    eprintln!("Skipping `{}`: {}", p.display(), why);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Matches the part of a page URL that holds the id. Only the path (and query) is used, so
+    /// relative links match too.
+    fn id_regex(&self) -> &Regex {
+        self.id_regex.get_or_init(|| self.compile_id_regex())
+    }
+
+    fn compile_id_regex(&self) -> Regex {
+        let path = match self.url.find("://") {
+            Some(scheme) => match self.url[scheme + 3..].find('/') {
+                Some(host) => &self.url[scheme + 3 + host..],
+                None => "/",
+            },
+            None => &self.url[..],
+        };
+        let id = match self.id_format {
+            IdFormat::Date => r"(\d{8})",
+            IdFormat::Number => r"(\d+)",
+        };
+
+        Regex::new(&regex::escape(path).replace(r"\{id\}", id)).expect("Couldn't compile regex")
+    }
+
+    fn selector(&self, selector: &str) -> Result<Selector, Error> {
+        Selector::parse(selector).map_err(|why| {
+            Error::Parse(format!(
+                "`{}` in the definition for {} isn't a valid selector: {:?}",
+                selector, self.name, why
+            ))
+        })
+    }
+
+    /// The text of the first thing `selector` matches that has any.
+    fn text(&self, html: &Html, selector: &Option<String>) -> Result<Option<String>, Error> {
+        match selector {
+            Some(selector) => Ok(html
+                .select(&self.selector(selector)?)
+                .map(|c| c.text().collect::<String>().trim().to_string())
+                .find(|t| !t.is_empty())),
+            None => Ok(None),
+        }
+    }
+
+    fn metadata(&self, url: &str, html: &Html) -> Result<PageMetadata, Error> {
+        let published = match (&self.selectors.date, self.id_format) {
+            (Some(selector), _) => self
+                .attr(html, selector, "datetime")?
+                .or(self.text(html, &self.selectors.date)?),
+            (None, IdFormat::Date) => self.page_id(url),
+            (None, IdFormat::Number) => None,
+        };
+
+        Ok(PageMetadata {
+            volume: self
+                .text(html, &self.selectors.volume)?
+                .and_then(|v| PageMetadata::number(&v)),
+            chapter: self.text(html, &self.selectors.chapter)?,
+            page_in_chapter: self
+                .text(html, &self.selectors.page_number)?
+                .and_then(|p| PageMetadata::number(&p)),
+            title: self.text(html, &self.selectors.title)?,
+            published: published.and_then(|d| PageMetadata::date(&d)),
+        })
+    }
+
+    /// The first `attribute` of whatever `selector` matches.
+    fn attr(&self, html: &Html, selector: &str, attribute: &str) -> Result<Option<String>, Error> {
+        Ok(html
+            .select(&self.selector(selector)?)
+            .find_map(|c| c.value().attr(attribute))
+            .map(|a| a.to_string()))
+    }
+}
+
+/// Whether `name` is only lowercase letters, digits and `-`.
+pub(crate) fn is_safe_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+impl Comic for ComicDefinition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn title(&self) -> &str {
+        self.title.as_deref().unwrap_or(&self.name)
+    }
+
+    fn first_page(&self) -> &str {
+        &self.first_page
+    }
+
+    fn page_url(&self, id: &str) -> String {
+        self.url.replace("{id}", id)
+    }
+
+    fn latest_url(&self) -> String {
+        self.latest_url.clone()
+    }
+
+    fn page_id(&self, url: &str) -> Option<String> {
+        self.id_regex()
+            .captures(url)
+            .map(|captures| captures[1].to_string())
+    }
+
+    fn parse_id(&self, input: &str) -> Result<String, Error> {
+        match self.id_format {
+            IdFormat::Date => {
+                let first = parse_date_id(&self.first_page)?;
+                let date = parse_date_id(input)?.clamp(first, Utc::now());
+
+                Ok(date.format("%Y%m%d").to_string())
+            }
+            IdFormat::Number => match input.trim().parse::<u64>() {
+                Ok(number) => Ok(number.to_string()),
+                Err(_) => Err(Error::Parse(format!("`{}` isn't a page number", input))),
+            },
+        }
+    }
+
+    fn step_back(&self, id: &str) -> Option<String> {
+        match self.id_format {
+            IdFormat::Date => {
+                let date = parse_date_id(id).ok()?;
+                let first = parse_date_id(&self.first_page).ok()?;
+
+                (date > first).then(|| (date - Duration::days(1)).format("%Y%m%d").to_string())
+            }
+            IdFormat::Number => id
+                .parse::<u64>()
+                .ok()?
+                .checked_sub(1)
+                .map(|n| n.to_string()),
+        }
+    }
+
+    fn scrape(&self, url: &str, html: &Html) -> Result<Scraped, Error> {
+        let images = html
+            .select(&self.selector(&self.selectors.image)?)
+            .filter_map(|c| c.value().attr("src"))
+            .map(|src| src.to_string())
+            .collect::<Vec<String>>();
+
+        if images.is_empty() {
+            return Err(Error::Scrape {
+                url: url.to_string(),
+                selector: self.selectors.image.clone(),
+            });
+        }
+
+        let link = |selector: &Option<String>| match selector {
+            Some(selector) => self.attr(html, selector, "href"),
+            None => Ok(None),
+        };
+
+        Ok(Scraped {
+            images,
+            previous_url: self.attr(html, &self.selectors.previous, "href")?,
+            next_url: self.attr(html, &self.selectors.next, "href")?,
+            first_url: link(&self.selectors.first)?,
+            last_url: link(&self.selectors.last)?,
+            metadata: self.metadata(url, html)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn definition(url: &str, id_format: &str) -> ComicDefinition {
+        toml::from_str(&format!(
+            r##"
+            name = "test"
+            url = "{}"
+            latest_url = "https://example.com/"
+            id_format = "{}"
+            first_page = "1"
+
+            [selectors]
+            image = "#comic img"
+            previous = "a[rel=prev]"
+            next = "a[rel=next]"
+            "##,
+            url, id_format
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn page_ids_come_out_of_full_and_relative_urls() {
+        let xkcd = definition("https://xkcd.com/{id}/", "number");
+
+        assert_eq!(
+            xkcd.page_id("https://xkcd.com/353/").as_deref(),
+            Some("353")
+        );
+        assert_eq!(xkcd.page_id("/353/").as_deref(), Some("353"));
+        assert_eq!(xkcd.page_id("https://xkcd.com/"), None);
+        assert_eq!(xkcd.page_id("https://xkcd.com/about/"), None);
+    }
+
+    #[test]
+    fn url_punctuation_is_matched_literally() {
+        let dated = definition("https://example.com/comic.php?date={id}", "date");
+
+        assert_eq!(
+            dated
+                .page_id("https://example.com/comic.php?date=20090318")
+                .as_deref(),
+            Some("20090318")
+        );
+        assert_eq!(
+            dated.page_id("/comic.php?date=20090318").as_deref(),
+            Some("20090318")
+        );
+        assert_eq!(dated.page_id("/comicXphp?date=20090318"), None);
+        assert_eq!(dated.page_id("/comic.php?date=2009031"), None);
+        assert_eq!(dated.id_regex().as_str(), r"/comic\.php\?date=(\d{8})");
+    }
+
+    #[test]
+    fn urls_without_a_scheme_are_matched_as_they_are() {
+        let bare = definition("{id}", "number");
+
+        assert_eq!(
+            bare.page_id("https://example.com/12").as_deref(),
+            Some("12")
+        );
+    }
+
+    #[test]
+    fn names_have_to_be_safe_as_directory_names() {
+        assert!(is_safe_name("xkcd"));
+        assert!(is_safe_name("girl-genius-2"));
+        for name in ["", "../x", "/abs/path", "Caps", "with space", "a.b", "é"] {
+            assert!(!is_safe_name(name), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn a_broken_definition_only_breaks_its_own_comic() {
+        let dir = std::env::temp_dir().join(format!("ggg-definitions-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let good = toml::to_string(&definition("https://example.com/{id}/", "number")).unwrap();
+        fs::write(dir.join("test.toml"), good).unwrap();
+        fs::write(dir.join("broken.toml"), "name = ").unwrap();
+
+        let definitions = ComicDefinition::read_dir(&dir);
+        let found = crate::comic::find_comic("test", &dir).map(|c| c.name().to_string());
+        let broken = crate::comic::find_comic("broken", &dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(definitions.len(), 1);
+        assert_eq!(found.unwrap(), "test");
+        match broken {
+            Err(Error::Parse(why)) => assert!(why.contains("broken.toml"), "{}", why),
+            other => panic!(
+                "expected a parse error, got {:?}",
+                other.map(|c| c.name().to_string())
+            ),
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use std::fmt;
+
+use reqwest::StatusCode;
+
+/// Everything that can go wrong while fetching, scraping or caching a page.
+#[derive(Debug)]
+pub enum Error {
+    /// The request never got a response (no connection, DNS failure, timeout, ...).
+    Network(reqwest::Error),
+    /// The server answered, but not with a success status.
+    Status { url: String, status: StatusCode },
+    /// Something (a response, a date string, ...) didn't look like what we expected.
+    Parse(String),
+    /// A selector we rely on matched nothing on the page.
+    Scrape { url: String, selector: String },
+    /// Reading or writing something on disk failed.
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    /// The cache directory or its index is unusable.
+    Cache(String),
+    /// We're offline, and the page (described by the string) isn't in the cache.
+    NotCached(String),
+    /// The server sent back something that isn't an image we know how to show.
+    NotAnImage {
+        url: String,
+        content_type: Option<String>,
+    },
+    /// Writing an export (a CBZ, an EPUB, ...) failed.
+    Export(String),
+    /// A bookmark couldn't be made, found, renamed or deleted.
+    Bookmark(String),
+    /// The web server (`ggg serve`) couldn't start, or stopped.
+    Serve(String),
+    /// The user pressed Ctrl-C.
+    Interrupted,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Network(why) => write!(f, "Couldn't reach the server: {}", why),
+            Error::Status { url, status } => write!(f, "`{}` returned {}", url, status),
+            Error::Parse(why) => write!(f, "{}", why),
+            Error::Scrape { url, selector } => {
+                write!(f, "`{}` matched nothing on `{}`", selector, url)
+            }
+            Error::Io { path, source } => write!(f, "Couldn't access `{}`: {}", path, source),
+            Error::Cache(why) => write!(f, "Cache error: {}", why),
+            Error::NotCached(what) => write!(f, "{} isn't cached", what),
+            Error::NotAnImage { url, content_type } => match content_type {
+                Some(content_type) => write!(f, "`{}` isn't an image (it's {})", url, content_type),
+                None => write!(f, "`{}` isn't an image", url),
+            },
+            Error::Export(why) => write!(f, "{}", why),
+            Error::Bookmark(why) => write!(f, "{}", why),
+            Error::Serve(why) => write!(f, "The web server failed: {}", why),
+            Error::Interrupted => write!(f, "Interrupted"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Network(why) => Some(why),
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(why: reqwest::Error) -> Self {
+        Error::Network(why)
+    }
+}
+
+impl Error {
+    pub(crate) fn io(path: impl Into<String>, source: std::io::Error) -> Self {
+        Error::Io {
+            path: path.into(),
+            source,
+        }
+    }
+}
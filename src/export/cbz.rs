@@ -0,0 +1,118 @@
+use std::{io::Write, path::Path};
+
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    comic::Comic,
+    export::{escape, for_each_page, write_atomically, zip_error, ExportPage, Range},
+    Error,
+};
+
+/// Exports `range` as a CBZ: the images in reading order, plus a `ComicInfo.xml` that comic
+/// reader apps use for the title, dates and chapter bookmarks. Returns how many pages it has.
+pub async fn export_cbz(
+    comic: &dyn Comic,
+    cache_dir: &String,
+    range: &Range,
+    offline: bool,
+    out: &Path,
+    mut progress: impl FnMut(&ExportPage),
+) -> Result<usize, Error> {
+    write_atomically(out, |file| async move {
+        let mut zip = ZipWriter::new(file);
+        // the images are already compressed, so squeezing them again is a waste of time
+        let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+        let mut pages = vec![];
+
+        for_each_page(comic, cache_dir, range, offline, |page| {
+            let image = page.read_image()?;
+            let name = format!(
+                "{:05}-{}.{}",
+                page.number + 1,
+                page.id(),
+                page.format.extension()
+            );
+            zip.start_file(name, options).map_err(zip_error)?;
+            zip.write_all(&image).map_err(zip_error)?;
+
+            progress(&page);
+            pages.push((page, image.len()));
+            Ok(())
+        })
+        .await?;
+
+        zip.start_file("ComicInfo.xml", options)
+            .map_err(zip_error)?;
+        zip.write_all(comic_info(comic, range, &pages).as_bytes())
+            .map_err(zip_error)?;
+        zip.finish().map_err(zip_error)?;
+
+        Ok(pages.len())
+    })
+    .await
+}
+
+/// The ComicInfo.xml for `pages`, with how big each page's image is.
+fn comic_info(comic: &dyn Comic, range: &Range, pages: &[(ExportPage, usize)]) -> String {
+    let first = &pages[0].0;
+    let last = &pages[pages.len() - 1].0;
+    let span = format!("{} to {}", first.heading(), last.heading());
+    let title = match (&range.chapter, first.chapter()) {
+        (Some(_), Some(label)) => label.to_string(),
+        _ => span.clone(),
+    };
+
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <ComicInfo xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" \
+         xmlns:xsd=\"http://www.w3.org/2001/XMLSchema\">\n",
+    );
+    xml.push_str(&format!("  <Title>{}</Title>\n", escape(&title)));
+    xml.push_str(&format!("  <Series>{}</Series>\n", escape(comic.title())));
+    if let Some(volume) = first.metadata().volume {
+        xml.push_str(&format!("  <Volume>{}</Volume>\n", volume));
+    }
+    xml.push_str(&format!(
+        "  <Summary>Pages from {}.</Summary>\n",
+        escape(&span)
+    ));
+    if let Some(date) = first.date() {
+        let mut parts = date.split('-');
+        for tag in ["Year", "Month", "Day"] {
+            let part = parts.next().unwrap_or_default().trim_start_matches('0');
+            xml.push_str(&format!("  <{}>{}</{}>\n", tag, part, tag));
+        }
+    }
+    xml.push_str(&format!(
+        "  <Web>{}</Web>\n",
+        escape(&comic.page_url(first.id()))
+    ));
+    xml.push_str(&format!("  <PageCount>{}</PageCount>\n", pages.len()));
+
+    xml.push_str("  <Pages>\n");
+    let mut previous_chapter = None;
+    for (page, size) in pages {
+        let mut attributes = format!(
+            "Image=\"{}\" ImageSize=\"{}\" Key=\"{}\"",
+            page.number,
+            size,
+            escape(&page.date().unwrap_or_else(|| page.id().to_string()))
+        );
+        if page.number == 0 {
+            attributes.push_str(" Type=\"FrontCover\"");
+        }
+        // readers list bookmarks as a table of contents, so only mark where storylines start
+        if page.chapter().is_some() && page.chapter() != previous_chapter {
+            attributes.push_str(&format!(
+                " Bookmark=\"{}\"",
+                escape(page.chapter().unwrap_or_default())
+            ));
+        }
+        previous_chapter = page.chapter();
+
+        xml.push_str(&format!("    <Page {} />\n", attributes));
+    }
+    xml.push_str("  </Pages>\n</ComicInfo>\n");
+
+    xml
+}
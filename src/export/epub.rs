@@ -0,0 +1,232 @@
+use std::{io::Write, path::Path};
+
+use chrono::Utc;
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    comic::Comic,
+    export::{escape, for_each_page, write_atomically, zip_error, ExportPage, Range},
+    Error,
+};
+
+/// Exports `range` as a fixed-layout EPUB 3, one page per image. The first page is the cover,
+/// and the navigation document lists every page by date, grouped by chapter. Returns how many
+/// pages it has.
+pub async fn export_epub(
+    comic: &dyn Comic,
+    cache_dir: &String,
+    range: &Range,
+    offline: bool,
+    out: &Path,
+    mut progress: impl FnMut(&ExportPage),
+) -> Result<usize, Error> {
+    write_atomically(out, |file| async move {
+        let mut zip = ZipWriter::new(file);
+        let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+
+        // readers find out what the file is from `mimetype`, so it has to come first, uncompressed
+        zip.start_file("mimetype", stored).map_err(zip_error)?;
+        zip.write_all(b"application/epub+zip").map_err(zip_error)?;
+        zip.start_file("META-INF/container.xml", stored)
+            .map_err(zip_error)?;
+        zip.write_all(CONTAINER.as_bytes()).map_err(zip_error)?;
+
+        let mut pages = vec![];
+        for_each_page(comic, cache_dir, range, offline, |page| {
+            let image = page.read_image()?;
+            let (width, height) = page.dimensions()?;
+
+            zip.start_file(format!("OEBPS/{}", image_path(&page)), stored)
+                .map_err(zip_error)?;
+            zip.write_all(&image).map_err(zip_error)?;
+            zip.start_file(format!("OEBPS/{}", page_path(&page)), stored)
+                .map_err(zip_error)?;
+            zip.write_all(page_xhtml(&page, width, height).as_bytes())
+                .map_err(zip_error)?;
+
+            progress(&page);
+            pages.push(page);
+            Ok(())
+        })
+        .await?;
+
+        zip.start_file("OEBPS/nav.xhtml", stored)
+            .map_err(zip_error)?;
+        zip.write_all(nav_xhtml(comic, &pages).as_bytes())
+            .map_err(zip_error)?;
+        zip.start_file("OEBPS/content.opf", stored)
+            .map_err(zip_error)?;
+        zip.write_all(content_opf(comic, range, &pages).as_bytes())
+            .map_err(zip_error)?;
+        zip.finish().map_err(zip_error)?;
+
+        Ok(pages.len())
+    })
+    .await
+}
+
+const CONTAINER: &str = "<?xml version=\"1.0\" encoding=\"utf-8\"?>
+<container version=\"1.0\" xmlns=\"urn:oasis:names:tc:opendocument:xmlns:container\">
+  <rootfiles>
+    <rootfile full-path=\"OEBPS/content.opf\" media-type=\"application/oebps-package+xml\"/>
+  </rootfiles>
+</container>
+";
+
+fn image_path(page: &ExportPage) -> String {
+    format!(
+        "images/{:05}-{}.{}",
+        page.number + 1,
+        page.id(),
+        page.format.extension()
+    )
+}
+
+fn page_path(page: &ExportPage) -> String {
+    format!("pages/{:05}-{}.xhtml", page.number + 1, page.id())
+}
+
+/// A page that's just the image, sized to fill the reader's screen.
+fn page_xhtml(page: &ExportPage, width: u32, height: u32) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>
+<!DOCTYPE html>
+<html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\">
+<head>
+  <title>{title}</title>
+  <meta name=\"viewport\" content=\"width={width}, height={height}\"/>
+  <style>body {{ margin: 0; }} img {{ display: block; width: {width}px; height: {height}px; }}</style>
+</head>
+<body>
+  <img src=\"../{image}\" alt=\"{title}\"/>
+</body>
+</html>
+",
+        title = escape(&page.heading()),
+        width = width,
+        height = height,
+        image = image_path(page),
+    )
+}
+
+/// The table of contents: every page by date, grouped under the chapter it's in.
+fn nav_xhtml(comic: &dyn Comic, pages: &[ExportPage]) -> String {
+    let mut toc = String::new();
+    let mut chapter: Option<Option<&str>> = None;
+    for page in pages {
+        let when = page.date().unwrap_or_else(|| format!("#{}", page.id()));
+        if chapter != Some(page.chapter()) {
+            if let Some(Some(_)) = chapter {
+                toc.push_str("      </ol></li>\n");
+            }
+            if let Some(label) = page.chapter() {
+                toc.push_str(&format!(
+                    "      <li><a href=\"{}\">{}</a><ol>\n",
+                    page_path(page),
+                    escape(label)
+                ));
+            }
+            chapter = Some(page.chapter());
+        }
+        toc.push_str(&format!(
+            "        <li><a href=\"{}\">{}</a></li>\n",
+            page_path(page),
+            escape(&when)
+        ));
+    }
+    if let Some(Some(_)) = chapter {
+        toc.push_str("      </ol></li>\n");
+    }
+
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>
+<!DOCTYPE html>
+<html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\">
+<head><title>{title}</title></head>
+<body>
+  <nav epub:type=\"toc\" id=\"toc\">
+    <h1>{title}</h1>
+    <ol>
+{toc}    </ol>
+  </nav>
+  <nav epub:type=\"landmarks\" hidden=\"hidden\">
+    <ol>
+      <li><a epub:type=\"cover\" href=\"{cover}\">Cover</a></li>
+      <li><a epub:type=\"bodymatter\" href=\"{cover}\">Start</a></li>
+    </ol>
+  </nav>
+</body>
+</html>
+",
+        title = escape(comic.title()),
+        toc = toc,
+        cover = page_path(&pages[0]),
+    )
+}
+
+fn content_opf(comic: &dyn Comic, range: &Range, pages: &[ExportPage]) -> String {
+    let first = &pages[0];
+    let last = &pages[pages.len() - 1];
+    let span = format!("{} to {}", first.heading(), last.heading());
+    let title = match (&range.chapter, first.chapter()) {
+        (Some(_), Some(label)) => format!("{}: {}", comic.title(), label),
+        _ => format!("{}: {}", comic.title(), span),
+    };
+
+    let mut manifest = String::from(
+        "    <item id=\"nav\" href=\"nav.xhtml\" media-type=\"application/xhtml+xml\" properties=\"nav\"/>\n",
+    );
+    let mut spine = String::new();
+    for page in pages {
+        let n = page.number + 1;
+        manifest.push_str(&format!(
+            "    <item id=\"img-{n:05}\" href=\"{}\" media-type=\"{}\"{}/>\n",
+            image_path(page),
+            page.format.media_type(),
+            if page.number == 0 {
+                " properties=\"cover-image\""
+            } else {
+                ""
+            },
+        ));
+        manifest.push_str(&format!(
+            "    <item id=\"page-{n:05}\" href=\"{}\" media-type=\"application/xhtml+xml\"/>\n",
+            page_path(page),
+        ));
+        spine.push_str(&format!("    <itemref idref=\"page-{n:05}\"/>\n"));
+    }
+
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>
+<package xmlns=\"http://www.idpf.org/2007/opf\" version=\"3.0\" unique-identifier=\"id\" prefix=\"rendition: http://www.idpf.org/vocab/rendition/#\">
+  <metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\">
+    <dc:identifier id=\"id\">{identifier}</dc:identifier>
+    <dc:title>{title}</dc:title>
+    <dc:language>en</dc:language>
+    <dc:source>{source}</dc:source>
+    <dc:description>Pages from {span}.</dc:description>
+    <meta property=\"dcterms:modified\">{modified}</meta>
+    <meta property=\"rendition:layout\">pre-paginated</meta>
+    <meta property=\"rendition:spread\">none</meta>
+    <meta name=\"cover\" content=\"img-00001\"/>
+  </metadata>
+  <manifest>
+{manifest}  </manifest>
+  <spine>
+{spine}  </spine>
+</package>
+",
+        identifier = escape(&format!(
+            "urn:ggg:{}:{}-{}",
+            comic.name(),
+            first.id(),
+            last.id()
+        )),
+        title = escape(&title),
+        source = escape(&comic.page_url(first.id())),
+        span = escape(&span),
+        modified = Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+        manifest = manifest,
+        spine = spine,
+    )
+}
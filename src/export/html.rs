@@ -0,0 +1,247 @@
+use std::{fs, path::Path};
+
+use crate::{
+    comic::Comic,
+    export::{escape, for_each_page, ExportPage, Range},
+    Error,
+};
+
+/// Exports `range` as a static website in the directory `out`: an `index.html` listing every
+/// page by date under its chapter, and a page for each image with links to the ones either
+/// side, which the arrow keys follow. It only needs a browser, so it can be read offline or put
+/// on any file server. Files already in `out` are overwritten. Returns how many pages it has.
+pub async fn export_html(
+    comic: &dyn Comic,
+    cache_dir: &String,
+    range: &Range,
+    offline: bool,
+    out: &Path,
+    mut progress: impl FnMut(&ExportPage),
+) -> Result<usize, Error> {
+    if out.is_file() {
+        return Err(Error::Export(format!(
+            "`{}` is a file, but a website needs a directory",
+            out.display()
+        )));
+    }
+    for dir in [out.join("images"), out.join("pages")] {
+        fs::create_dir_all(&dir).map_err(|why| Error::io(dir.display().to_string(), why))?;
+    }
+
+    // the images are copied straight away, before pruning can get to them; the pages can only
+    // be written once we know what comes after each one
+    let mut pages = vec![];
+    for_each_page(comic, cache_dir, range, offline, |page| {
+        let to = out.join(image_path(&page));
+        fs::copy(&page.page.path, &to).map_err(|why| Error::io(to.display().to_string(), why))?;
+
+        progress(&page);
+        pages.push(page);
+        Ok(())
+    })
+    .await?;
+
+    for (i, page) in pages.iter().enumerate() {
+        let links = Links {
+            first: &pages[0],
+            previous: i.checked_sub(1).map(|p| &pages[p]),
+            next: pages.get(i + 1),
+            last: &pages[pages.len() - 1],
+        };
+        write(&out.join(page_path(page)), &page_html(comic, page, &links))?;
+    }
+    write(&out.join("index.html"), &index_html(comic, &pages))?;
+    write(&out.join("style.css"), STYLE)?;
+    write(&out.join("reader.js"), READER)?;
+
+    Ok(pages.len())
+}
+
+fn write(path: &Path, contents: &str) -> Result<(), Error> {
+    fs::write(path, contents).map_err(|why| Error::io(path.display().to_string(), why))
+}
+
+fn image_path(page: &ExportPage) -> String {
+    format!("images/{}.{}", page.id(), page.format.extension())
+}
+
+fn page_path(page: &ExportPage) -> String {
+    format!("pages/{}.html", page.id())
+}
+
+/// Where a page's navigation goes.
+struct Links<'a> {
+    first: &'a ExportPage,
+    previous: Option<&'a ExportPage>,
+    next: Option<&'a ExportPage>,
+    last: &'a ExportPage,
+}
+
+impl Links<'_> {
+    /// `<link>` tags for the head, which is what `reader.js` follows.
+    fn head(&self) -> String {
+        let mut head = String::new();
+        for (rel, page) in [
+            ("first", Some(self.first)),
+            ("prev", self.previous),
+            ("next", self.next),
+            ("last", Some(self.last)),
+        ] {
+            if let Some(page) = page {
+                head.push_str(&format!(
+                    "  <link rel=\"{}\" href=\"../{}\">\n",
+                    rel,
+                    page_path(page)
+                ));
+            }
+        }
+        head
+    }
+
+    fn nav(&self, current: &ExportPage) -> String {
+        let link = |text: &str, page: Option<&ExportPage>| match page {
+            Some(page) if page.number != current.number => {
+                format!("<a href=\"../{}\">{}</a>", page_path(page), text)
+            }
+            _ => format!("<span class=\"disabled\">{}</span>", text),
+        };
+
+        format!(
+            "  <nav>\n    {}\n    {}\n    <a href=\"../index.html#p-{}\">Index</a>\n    {}\n    {}\n  </nav>\n",
+            link("&laquo; First", Some(self.first)),
+            link("&lsaquo; Previous", self.previous),
+            current.id(),
+            link("Next &rsaquo;", self.next),
+            link("Last &raquo;", Some(self.last)),
+        )
+    }
+}
+
+fn page_html(comic: &dyn Comic, page: &ExportPage, links: &Links) -> String {
+    let image = format!(
+        "<img src=\"../{}\" alt=\"{}\">",
+        image_path(page),
+        escape(&page.heading())
+    );
+    // clicking the page turns it, like in the app
+    let image = match links.next {
+        Some(next) => format!("<a href=\"../{}\">{}</a>", page_path(next), image),
+        None => image,
+    };
+
+    format!(
+        "<!DOCTYPE html>
+<html lang=\"en\">
+<head>
+  <meta charset=\"utf-8\">
+  <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">
+  <title>{heading} - {title}</title>
+  <link rel=\"stylesheet\" href=\"../style.css\">
+  <link rel=\"index\" href=\"../index.html\">
+{head}  <script src=\"../reader.js\" defer></script>
+</head>
+<body>
+  <h1>{heading}</h1>
+{nav}  <main>{image}</main>
+{nav}  <footer><a href=\"{source}\">On the original site</a> &middot; &larr; and &rarr; turn pages, i goes to the index</footer>
+</body>
+</html>
+",
+        heading = escape(&page.heading()),
+        title = escape(comic.title()),
+        head = links.head(),
+        nav = links.nav(page),
+        image = image,
+        source = escape(&comic.page_url(page.id())),
+    )
+}
+
+/// Every page by date, under the chapter it's in.
+fn index_html(comic: &dyn Comic, pages: &[ExportPage]) -> String {
+    let mut list = String::new();
+    let mut chapter: Option<Option<&str>> = None;
+    for page in pages {
+        if chapter != Some(page.chapter()) {
+            if chapter.is_some() {
+                list.push_str("  </ol>\n");
+            }
+            if let Some(label) = page.chapter() {
+                list.push_str(&format!("  <h2>{}</h2>\n", escape(label)));
+            }
+            list.push_str("  <ol>\n");
+            chapter = Some(page.chapter());
+        }
+        list.push_str(&format!(
+            "    <li id=\"p-{}\"><a href=\"{}\">{}</a></li>\n",
+            page.id(),
+            page_path(page),
+            escape(&page.date().unwrap_or_else(|| format!("#{}", page.id())))
+        ));
+    }
+    list.push_str("  </ol>\n");
+
+    let first = &pages[0];
+    let last = &pages[pages.len() - 1];
+    format!(
+        "<!DOCTYPE html>
+<html lang=\"en\">
+<head>
+  <meta charset=\"utf-8\">
+  <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">
+  <title>{title}</title>
+  <link rel=\"stylesheet\" href=\"style.css\">
+  <link rel=\"first\" href=\"{first}\">
+  <link rel=\"next\" href=\"{first}\">
+  <link rel=\"last\" href=\"{last}\">
+  <script src=\"reader.js\" defer></script>
+</head>
+<body class=\"index\">
+  <h1>{title}</h1>
+  <p>{count} pages, from {from} to {to}. <a href=\"{first}\">Start reading</a></p>
+{list}</body>
+</html>
+",
+        title = escape(comic.title()),
+        first = page_path(first),
+        last = page_path(last),
+        count = pages.len(),
+        from = escape(&first.heading()),
+        to = escape(&last.heading()),
+        list = list,
+    )
+}
+
+const STYLE: &str = "body { margin: 0 auto; padding: 0.5em; max-width: 100%; font-family: sans-serif; background: #222; color: #ddd; text-align: center; }
+a { color: #9cf; }
+h1 { font-size: 1.2em; font-weight: normal; }
+nav { display: flex; justify-content: center; gap: 1.5em; margin: 0.5em 0; }
+nav .disabled { color: #666; }
+main img { max-width: 100%; height: auto; }
+footer { margin: 1em 0; font-size: 0.8em; color: #999; }
+body.index { max-width: 40em; text-align: left; }
+body.index ol { columns: 12em; }
+";
+
+/// Keyboard navigation, going by the `<link>` tags in each page's head.
+const READER: &str = "// Left and right (or h and l) turn pages, Home and End go to the first and
+// last pages, and i goes to the index.
+document.addEventListener(\"keydown\", function (event) {
+  if (event.altKey || event.ctrlKey || event.metaKey) {
+    return;
+  }
+  var rel = {
+    ArrowLeft: \"prev\",
+    h: \"prev\",
+    ArrowRight: \"next\",
+    l: \"next\",
+    Home: \"first\",
+    End: \"last\",
+    i: \"index\",
+  }[event.key];
+  var link = rel && document.querySelector('link[rel=\"' + rel + '\"]');
+  if (link) {
+    event.preventDefault();
+    window.location.href = link.href;
+  }
+});
+";
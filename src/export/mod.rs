@@ -0,0 +1,216 @@
+//! Turning a range of pages into something other programs can read.
+
+pub mod cbz;
+pub mod epub;
+pub mod html;
+pub mod pdf;
+
+use std::{cmp::Ordering, fs, path::Path};
+
+use crate::{
+    comic::{Comic, PageMetadata},
+    compare_ids, id_date,
+    image_format::ImageFormat,
+    page, refresh_links, resolve_page,
+    structs::GggCacheData,
+    Error, Page,
+};
+
+/// Which pages to export. With a chapter, `from` and `to` default to where the chapter starts
+/// and ends; otherwise they default to the first and newest pages.
+#[derive(Debug, Clone, Default)]
+pub struct Range {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    /// Only pages whose chapter (`PageMetadata::chapter`) is this, ignoring case. Chapters can
+    /// only be found once their pages have been cached (a `ggg sync` does the whole archive).
+    pub chapter: Option<String>,
+}
+
+impl Range {
+    fn wants(&self, page: &GggCacheData) -> bool {
+        match &self.chapter {
+            Some(chapter) => page
+                .chapter()
+                .is_some_and(|c| c.eq_ignore_ascii_case(chapter)),
+            None => true,
+        }
+    }
+
+    /// Where the chapter starts and ends, going by the cache index.
+    fn chapter_bounds(&self, cache_dir: &str) -> Result<Option<(String, String)>, Error> {
+        let chapter = match &self.chapter {
+            Some(chapter) => chapter,
+            None => return Ok(None),
+        };
+        let mut ids = GggCacheData::read(cache_dir.to_string())
+            .into_iter()
+            .filter(|p| self.wants(p))
+            .map(|p| p.id)
+            .collect::<Vec<String>>();
+        ids.sort_by(|a, b| compare_ids(a, b));
+
+        match (ids.first(), ids.last()) {
+            (Some(first), Some(last)) => Ok(Some((first.clone(), last.clone()))),
+            _ => Err(Error::Parse(format!(
+                "No cached page is in a chapter called `{}`. Run `ggg sync` so chapters can be found.",
+                chapter
+            ))),
+        }
+    }
+}
+
+/// One exported page, as an exporter sees it.
+#[derive(Debug, Clone)]
+pub struct ExportPage {
+    /// Where the page falls in the export, from 0.
+    pub number: usize,
+    pub page: Page,
+    pub format: ImageFormat,
+}
+
+impl ExportPage {
+    pub fn id(&self) -> &str {
+        &self.page.data.id
+    }
+
+    pub fn chapter(&self) -> Option<&str> {
+        self.page.data.chapter()
+    }
+
+    pub fn metadata(&self) -> &PageMetadata {
+        &self.page.data.metadata
+    }
+
+    /// The page's date as `YYYY-MM-DD`, if the site said when it went up or the id is a date.
+    pub fn date(&self) -> Option<String> {
+        self.metadata()
+            .published
+            .clone()
+            .or_else(|| id_date(self.id()))
+    }
+
+    /// Something like `2009-03-18 (Volume 9)`, or `#123 (Chapter 4)`.
+    pub fn heading(&self) -> String {
+        let when = self.date().unwrap_or_else(|| format!("#{}", self.id()));
+        match self.chapter() {
+            Some(chapter) => format!("{} ({})", when, chapter),
+            None => when,
+        }
+    }
+
+    /// The image's width and height, in pixels.
+    pub fn dimensions(&self) -> Result<(u32, u32), Error> {
+        image::image_dimensions(&self.page.path).map_err(|why| {
+            Error::Export(format!(
+                "Couldn't read the size of `{}`: {}",
+                self.page.path, why
+            ))
+        })
+    }
+
+    pub fn read_image(&self) -> Result<Vec<u8>, Error> {
+        fs::read(&self.page.path).map_err(|why| Error::io(self.page.path.clone(), why))
+    }
+}
+
+/// Walks `range` in reading order, calling `each` on every page as soon as its image is on
+/// disk. Pages that aren't cached yet are downloaded (unless `offline`, when they're an error).
+/// Handing pages over one at a time means an export never needs the whole range in memory,
+/// and cache pruning can't delete a page before it's been written out.
+pub async fn for_each_page(
+    comic: &dyn Comic,
+    cache_dir: &String,
+    range: &Range,
+    offline: bool,
+    mut each: impl FnMut(ExportPage) -> Result<(), Error>,
+) -> Result<usize, Error> {
+    let chapter = range.chapter_bounds(cache_dir)?;
+    let from = match (&range.from, &chapter) {
+        (Some(from), _) => from.clone(),
+        (None, Some((first, _))) => first.clone(),
+        (None, None) => comic.first_page().to_string(),
+    };
+    let to = match (&range.to, &chapter) {
+        (Some(to), _) => Some(resolve_page(comic, to.clone(), cache_dir, offline).await?),
+        (None, Some((_, last))) => Some(last.clone()),
+        (None, None) => None,
+    };
+
+    // resolving finds the nearest page at or before `from`, which might be too early
+    let mut current = Some(resolve_page(comic, from.clone(), cache_dir, offline).await?);
+    let mut exported = 0;
+    while let Some(id) = current {
+        if to
+            .as_ref()
+            .is_some_and(|to| compare_ids(&id, to) == Ordering::Greater)
+        {
+            break;
+        }
+
+        let page = page(comic, id.clone(), cache_dir, offline).await?;
+        if compare_ids(&id, &from) != Ordering::Less && range.wants(&page.data) {
+            each(ExportPage {
+                number: exported,
+                format: page.format(),
+                page: page.clone(),
+            })?;
+            exported += 1;
+        }
+
+        current = match page.data.next {
+            Some(next) => Some(next),
+            None if to.as_ref() == Some(&id) => None,
+            // offline, the newest cached page is as far as "the newest page" goes
+            None if offline && to.is_none() => None,
+            None if offline => {
+                return Err(Error::NotCached(format!("The page after {}", id)));
+            }
+            // either this was the newest page when it was cached, or it really is the newest
+            None => refresh_links(comic, id, cache_dir).await?.next,
+        };
+    }
+
+    if exported == 0 {
+        return Err(Error::Parse("There are no pages in that range".to_string()));
+    }
+    Ok(exported)
+}
+
+/// Writes an export to `{out}.part` with `write`, then moves it into place, so a failed export
+/// never leaves a half-written file where the real one should be.
+pub(crate) async fn write_atomically<F, Fut>(out: &Path, write: F) -> Result<usize, Error>
+where
+    F: FnOnce(fs::File) -> Fut,
+    Fut: std::future::Future<Output = Result<usize, Error>>,
+{
+    let part = out.with_extension(match out.extension().and_then(|e| e.to_str()) {
+        Some(extension) => format!("{}.part", extension),
+        None => "part".to_string(),
+    });
+    let file = fs::File::create(&part).map_err(|why| Error::io(part.display().to_string(), why))?;
+
+    match write(file).await {
+        Ok(pages) => {
+            fs::rename(&part, out).map_err(|why| Error::io(out.display().to_string(), why))?;
+            Ok(pages)
+        }
+        Err(why) => {
+            let _ = fs::remove_file(&part);
+            Err(why)
+        }
+    }
+}
+
+pub(crate) fn zip_error(why: impl std::fmt::Display) -> Error {
+    Error::Export(format!("Couldn't write the archive: {}", why))
+}
+
+/// Escapes text for XML (and HTML).
+pub(crate) fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
@@ -0,0 +1,466 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use flate2::{write::ZlibEncoder, Compression};
+use pdf_writer::{
+    types::PageMode,
+    writers::{Catalog, DocumentInfo},
+    Chunk, Content, Filter, Finish, Name, Rect, Ref, Str, TextStr,
+};
+
+use crate::{
+    comic::Comic,
+    export::{for_each_page, write_atomically, ExportPage, Range},
+    image_format::ImageFormat,
+    Error,
+};
+
+/// The paper an exported PDF is laid out on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Paper {
+    #[default]
+    A4,
+    Letter,
+}
+
+impl Paper {
+    /// Width and height, in points.
+    fn size(&self) -> (f32, f32) {
+        match self {
+            Paper::A4 => (595.0, 842.0),
+            Paper::Letter => (612.0, 792.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PdfOptions {
+    pub paper: Paper,
+    /// Print each page's date (and chapter) under it.
+    pub captions: bool,
+}
+
+const MARGIN: f32 = 36.0;
+const CAPTION_HEIGHT: f32 = 24.0;
+const CAPTION_SIZE: f32 = 10.0;
+
+/// Exports `range` as a PDF with one page per image, scaled to fit the paper. Each chapter gets
+/// an entry in the outline (the bookmarks panel). Returns how many pages it has.
+pub async fn export_pdf(
+    comic: &dyn Comic,
+    cache_dir: &String,
+    range: &Range,
+    offline: bool,
+    options: PdfOptions,
+    out: &Path,
+    mut progress: impl FnMut(&ExportPage),
+) -> Result<usize, Error> {
+    write_atomically(out, |file| async move {
+        let mut pdf = PdfFile::new(file, out)?;
+        let catalog = pdf.alloc();
+        let page_tree = pdf.alloc();
+        let font = options.captions.then(|| pdf.alloc());
+
+        let mut pages = vec![];
+        let mut chapters: Vec<(String, Ref)> = vec![];
+        for_each_page(comic, cache_dir, range, offline, |page| {
+            let page_id = pdf.alloc();
+            let image_id = pdf.alloc();
+            let content_id = pdf.alloc();
+
+            let image = Embedded::read(&page)?;
+            let mut chunk = Chunk::new();
+            let mut xobject = chunk.image_xobject(image_id, &image.data);
+            xobject.filter(image.filter);
+            xobject.width(image.width as i32);
+            xobject.height(image.height as i32);
+            if image.grey {
+                xobject.color_space().device_gray();
+            } else {
+                xobject.color_space().device_rgb();
+            }
+            xobject.bits_per_component(8);
+            xobject.finish();
+            pdf.write(image_id, &chunk)?;
+
+            let mut chunk = Chunk::new();
+            chunk.stream(content_id, &page_content(&page, &image, options));
+            pdf.write(content_id, &chunk)?;
+
+            let (width, height) = options.paper.size();
+            let mut chunk = Chunk::new();
+            let mut pdf_page = chunk.page(page_id);
+            pdf_page.media_box(Rect::new(0.0, 0.0, width, height));
+            pdf_page.parent(page_tree);
+            pdf_page.contents(content_id);
+            let mut resources = pdf_page.resources();
+            resources.x_objects().pair(Name(b"Im"), image_id);
+            if let Some(font) = font {
+                resources.fonts().pair(Name(b"F1"), font);
+            }
+            resources.finish();
+            pdf_page.finish();
+            pdf.write(page_id, &chunk)?;
+
+            // the outline is a table of contents, so only mark where storylines start
+            if let Some(label) = page.chapter() {
+                if chapters.last().map(|(l, _)| &l[..]) != Some(label) {
+                    chapters.push((label.to_string(), page_id));
+                }
+            }
+
+            progress(&page);
+            pages.push(page_id);
+            Ok(())
+        })
+        .await?;
+
+        if let Some(font) = font {
+            let mut chunk = Chunk::new();
+            chunk
+                .type1_font(font)
+                .base_font(Name(b"Helvetica"))
+                .encoding_predefined(Name(b"WinAnsiEncoding"));
+            pdf.write(font, &chunk)?;
+        }
+
+        let mut chunk = Chunk::new();
+        chunk
+            .pages(page_tree)
+            .kids(pages.iter().copied())
+            .count(pages.len() as i32);
+        pdf.write(page_tree, &chunk)?;
+
+        let outline = match chapters.is_empty() {
+            true => None,
+            false => Some(write_outline(&mut pdf, &chapters)?),
+        };
+
+        let info = pdf.alloc();
+        let mut chunk = Chunk::new();
+        chunk
+            .indirect(info)
+            .start::<DocumentInfo>()
+            .title(TextStr(comic.title()))
+            .producer(TextStr("ggg"));
+        pdf.write(info, &chunk)?;
+
+        let mut chunk = Chunk::new();
+        let mut root = chunk.indirect(catalog).start::<Catalog>();
+        root.pages(page_tree);
+        if let Some(outline) = outline {
+            root.outlines(outline);
+            root.page_mode(PageMode::UseOutlines);
+        }
+        root.finish();
+        pdf.write(catalog, &chunk)?;
+
+        pdf.finish(catalog, info)?;
+        Ok(pages.len())
+    })
+    .await
+}
+
+/// Draws `image` as big as it'll go on the paper, keeping its shape, with the caption under it.
+fn page_content(page: &ExportPage, image: &Embedded, options: PdfOptions) -> Vec<u8> {
+    let (paper_width, paper_height) = options.paper.size();
+    let bottom = match options.captions {
+        true => MARGIN + CAPTION_HEIGHT,
+        false => MARGIN,
+    };
+    let room_width = paper_width - 2.0 * MARGIN;
+    let room_height = paper_height - MARGIN - bottom;
+
+    let scale = (room_width / image.width as f32).min(room_height / image.height as f32);
+    let width = image.width as f32 * scale;
+    let height = image.height as f32 * scale;
+    let x = (paper_width - width) / 2.0;
+    let y = bottom + (room_height - height) / 2.0;
+
+    let mut content = Content::new();
+    content.save_state();
+    content.transform([width, 0.0, 0.0, height, x, y]);
+    content.x_object(Name(b"Im"));
+    content.restore_state();
+
+    if options.captions {
+        content.begin_text();
+        content.set_font(Name(b"F1"), CAPTION_SIZE);
+        content.next_line(x, y - CAPTION_HEIGHT + CAPTION_SIZE / 2.0);
+        content.show(Str(&win_ansi(&page.heading())));
+        content.end_text();
+    }
+
+    content.finish()
+}
+
+/// The outline, with an item per chapter that opens its first page. Returns the outline's id.
+fn write_outline(pdf: &mut PdfFile, chapters: &[(String, Ref)]) -> Result<Ref, Error> {
+    let outline = pdf.alloc();
+    let items = chapters.iter().map(|_| pdf.alloc()).collect::<Vec<Ref>>();
+
+    for (i, (label, page)) in chapters.iter().enumerate() {
+        let mut chunk = Chunk::new();
+        let mut item = chunk.outline_item(items[i]);
+        item.title(TextStr(label));
+        item.parent(outline);
+        if i > 0 {
+            item.prev(items[i - 1]);
+        }
+        if let Some(next) = items.get(i + 1) {
+            item.next(*next);
+        }
+        item.dest().page(*page).fit();
+        item.finish();
+        pdf.write(items[i], &chunk)?;
+    }
+
+    let mut chunk = Chunk::new();
+    chunk
+        .outline(outline)
+        .first(items[0])
+        .last(items[items.len() - 1])
+        .count(items.len() as i32);
+    pdf.write(outline, &chunk)?;
+
+    Ok(outline)
+}
+
+/// Helvetica only has Latin-1, more or less, so anything else is shown as `?`.
+fn win_ansi(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| match c as u32 {
+            0x20..=0x7E | 0xA0..=0xFF => c as u8,
+            _ => b'?',
+        })
+        .collect()
+}
+
+/// An image, ready to go in the PDF.
+struct Embedded {
+    data: Vec<u8>,
+    filter: Filter,
+    grey: bool,
+    width: u32,
+    height: u32,
+}
+
+impl Embedded {
+    /// Most JPEGs can go in as they are. Everything else is decoded, laid on white paper (so
+    /// transparent bits don't come out black), and compressed again.
+    fn read(page: &ExportPage) -> Result<Embedded, Error> {
+        if page.format == ImageFormat::Jpeg {
+            let data = page.read_image()?;
+            if let Some(components @ (1 | 3)) = jpeg_components(&data) {
+                let (width, height) = page.dimensions()?;
+                return Ok(Embedded {
+                    data,
+                    filter: Filter::DctDecode,
+                    grey: components == 1,
+                    width,
+                    height,
+                });
+            }
+        }
+
+        let image = image::open(&page.page.path).map_err(|why| {
+            Error::Export(format!("Couldn't decode `{}`: {}", page.page.path, why))
+        })?;
+        let grey = !image.color().has_color();
+        let rgba = image.to_rgba8();
+        let mut samples = Vec::with_capacity(rgba.len() / 4 * if grey { 1 } else { 3 });
+        for pixel in rgba.pixels() {
+            let [r, g, b, a] = pixel.0;
+            let on_white = |c: u8| ((c as u32 * a as u32 + 255 * (255 - a as u32)) / 255) as u8;
+            match grey {
+                true => samples.push(on_white(r)),
+                false => samples.extend([on_white(r), on_white(g), on_white(b)]),
+            }
+        }
+
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder
+            .write_all(&samples)
+            .and_then(|_| encoder.finish())
+            .map(|data| Embedded {
+                data,
+                filter: Filter::FlateDecode,
+                grey,
+                width: rgba.width(),
+                height: rgba.height(),
+            })
+            .map_err(|why| {
+                Error::Export(format!("Couldn't compress `{}`: {}", page.page.path, why))
+            })
+    }
+}
+
+/// How many colour components a JPEG has, going by its frame header. Only baseline and
+/// progressive 8-bit JPEGs count, since they're all a PDF reader is sure to understand.
+fn jpeg_components(bytes: &[u8]) -> Option<u8> {
+    let mut at = 2;
+    while at + 4 <= bytes.len() {
+        if bytes[at] != 0xFF {
+            return None;
+        }
+        let marker = bytes[at + 1];
+        if marker == 0xFF {
+            at += 1;
+            continue;
+        }
+        let length = u16::from_be_bytes([bytes[at + 2], bytes[at + 3]]) as usize;
+        match marker {
+            0xC0..=0xC2 if bytes.get(at + 4) == Some(&8) => return bytes.get(at + 9).copied(),
+            // any other frame type, or image data before a frame header
+            0xC3..=0xCF | 0xDA | 0xD9 if marker != 0xC4 && marker != 0xCC => return None,
+            _ => at += 2 + length,
+        }
+    }
+    None
+}
+
+/// Writes a PDF an object at a time, so the images never all have to be in memory at once.
+/// `pdf_writer::Pdf` keeps the whole file in memory, so this does the cross-reference table
+/// and trailer itself.
+struct PdfFile<'a> {
+    file: BufWriter<File>,
+    out: &'a Path,
+    written: u64,
+    /// Where each object starts, by id (id 0 is never used).
+    offsets: Vec<Option<u64>>,
+}
+
+impl<'a> PdfFile<'a> {
+    fn new(file: File, out: &'a Path) -> Result<PdfFile<'a>, Error> {
+        let mut pdf = PdfFile {
+            file: BufWriter::new(file),
+            out,
+            written: 0,
+            offsets: vec![None],
+        };
+        // the binary comment tells transfer programs not to mangle line endings
+        pdf.write_bytes(b"%PDF-1.7\n%\x80\x80\x80\x80\n")?;
+        Ok(pdf)
+    }
+
+    fn alloc(&mut self) -> Ref {
+        self.offsets.push(None);
+        Ref::new(self.offsets.len() as i32 - 1)
+    }
+
+    /// Writes `chunk`, which has to be just the object `id`.
+    fn write(&mut self, id: Ref, chunk: &Chunk) -> Result<(), Error> {
+        self.offsets[id.get() as usize] = Some(self.written);
+        self.write_bytes(chunk.as_bytes())
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.file
+            .write_all(bytes)
+            .map_err(|why| Error::io(self.out.display().to_string(), why))?;
+        self.written += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn finish(mut self, catalog: Ref, info: Ref) -> Result<(), Error> {
+        let xref = self.written;
+        // every entry is exactly 20 bytes, line ending included
+        let mut table = format!("xref\n0 {}\n", self.offsets.len());
+        for offset in &self.offsets {
+            match offset {
+                Some(offset) => table.push_str(&format!("{:010} 00000 n\r\n", offset)),
+                None => table.push_str("0000000000 65535 f\r\n"),
+            }
+        }
+        table.push_str(&format!(
+            "trailer\n<< /Size {} /Root {} 0 R /Info {} 0 R >>\nstartxref\n{}\n%%EOF\n",
+            self.offsets.len(),
+            catalog.get(),
+            info.get(),
+            xref
+        ));
+        self.write_bytes(table.as_bytes())?;
+        self.file
+            .flush()
+            .map_err(|why| Error::io(self.out.display().to_string(), why))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A JPEG made by the `image` crate, so it has the usual JFIF header before the frame.
+    fn jpeg(color: image::ColorType) -> Vec<u8> {
+        let channels = color.channel_count() as usize;
+        let mut bytes = vec![];
+        image::codecs::jpeg::JpegEncoder::new(&mut bytes)
+            .encode(&vec![128; 4 * 4 * channels], 4, 4, color)
+            .unwrap();
+        bytes
+    }
+
+    /// SOI, then a frame header with `marker`, `precision` and `components`, then SOS.
+    fn frame(marker: u8, precision: u8, components: u8) -> Vec<u8> {
+        let mut bytes = vec![0xFF, 0xD8, 0xFF, marker, 0x00, 0x0B, precision, 0, 4, 0, 4];
+        bytes.extend([components, 1, 0x11, 0]);
+        bytes.extend([0xFF, 0xDA, 0x00, 0x02]);
+        bytes
+    }
+
+    #[test]
+    fn jpeg_components_come_from_the_frame_header() {
+        assert_eq!(jpeg_components(&jpeg(image::ColorType::L8)), Some(1));
+        assert_eq!(jpeg_components(&jpeg(image::ColorType::Rgb8)), Some(3));
+        assert_eq!(jpeg_components(&frame(0xC2, 8, 3)), Some(3));
+    }
+
+    #[test]
+    fn unusual_jpegs_arent_passed_through() {
+        // lossless, 12-bit, and no frame header before the image data
+        assert_eq!(jpeg_components(&frame(0xC3, 8, 3)), None);
+        assert_eq!(jpeg_components(&frame(0xC1, 12, 3)), None);
+        assert_eq!(jpeg_components(&[0xFF, 0xD8, 0xFF, 0xDA, 0x00, 0x02]), None);
+        assert_eq!(jpeg_components(&[0xFF, 0xD8, 0xFF, 0xC0, 0x00]), None);
+        assert_eq!(jpeg_components(b"not a jpeg"), None);
+    }
+
+    #[test]
+    fn the_xref_table_points_at_every_object() {
+        let path = std::env::temp_dir().join(format!("ggg-xref-{}.pdf", std::process::id()));
+        let mut pdf = PdfFile::new(File::create(&path).unwrap(), &path).unwrap();
+        let (catalog, info, unused, number) = (pdf.alloc(), pdf.alloc(), pdf.alloc(), pdf.alloc());
+        // written out of order, with one id never written
+        for (id, value) in [(number, 42), (catalog, 1), (info, 2)] {
+            let mut chunk = Chunk::new();
+            chunk.indirect(id).primitive(value);
+            pdf.write(id, &chunk).unwrap();
+        }
+        pdf.finish(catalog, info).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let text = String::from_utf8_lossy(&bytes);
+        let startxref = text.rsplit("startxref\n").next().unwrap();
+        let xref = startxref.lines().next().unwrap().parse::<usize>().unwrap();
+        assert!(bytes[xref..].starts_with(b"xref\n0 5\n"));
+
+        let entries = &bytes[xref + "xref\n0 5\n".len()..];
+        for n in 0..5 {
+            let entry = std::str::from_utf8(&entries[n * 20..(n + 1) * 20]).unwrap();
+            assert!(entry.ends_with("\r\n"), "{:?}", entry);
+            if n == 0 || n == unused.get() as usize {
+                assert_eq!(entry, "0000000000 65535 f\r\n");
+                continue;
+            }
+            let offset = entry[..10].parse::<usize>().unwrap();
+            let object = format!("{} 0 obj", n);
+            assert!(bytes[offset..].starts_with(object.as_bytes()), "{}", object);
+        }
+        assert!(text.contains("/Size 5 /Root 1 0 R /Info 2 0 R"));
+        assert!(text.ends_with("%%EOF\n"));
+    }
+}
@@ -0,0 +1,163 @@
+use chrono::{DateTime, Duration, Utc};
+use scraper::{ElementRef, Html, Selector};
+
+use crate::{
+    comic::{Comic, PageMetadata, Scraped},
+    parse_date_id, Error,
+};
+
+/// Girl Genius, at girlgeniusonline.com. Page ids are the `YYYYMMDD` date the page went up.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GirlGenius;
+
+impl Comic for GirlGenius {
+    fn name(&self) -> &str {
+        "girl-genius"
+    }
+
+    fn title(&self) -> &str {
+        "Girl Genius"
+    }
+
+    fn first_page(&self) -> &str {
+        "20021104"
+    }
+
+    fn page_url(&self, id: &str) -> String {
+        match parse_date_id(id) {
+            Ok(date) => date_to_gg_string(date),
+            Err(_) => format!("{}?date={}", self.latest_url(), id),
+        }
+    }
+
+    fn latest_url(&self) -> String {
+        "https://www.girlgeniusonline.com/comic.php".to_string()
+    }
+
+    fn page_id(&self, url: &str) -> Option<String> {
+        parse_date_id(url)
+            .ok()
+            .map(|date| date.format("%Y%m%d").to_string())
+    }
+
+    /// Any `YYYYMMDD` date, pulled back to somewhere between the first page and today.
+    fn parse_id(&self, input: &str) -> Result<String, Error> {
+        let first = parse_date_id(self.first_page())?;
+        let date = parse_date_id(input)?.clamp(first, Utc::now());
+
+        Ok(date.format("%Y%m%d").to_string())
+    }
+
+    fn step_back(&self, id: &str) -> Option<String> {
+        let date = parse_date_id(id).ok()?;
+        let first = parse_date_id(self.first_page()).ok()?;
+
+        if date <= first {
+            None
+        } else {
+            Some((date - Duration::days(1)).format("%Y%m%d").to_string())
+        }
+    }
+
+    fn max_gap(&self) -> usize {
+        // it's never gone two weeks without a page
+        14
+    }
+
+    fn scrape(&self, url: &str, html: &Html) -> Result<Scraped, Error> {
+        let selector = Selector::parse("div[id=\"topnav\"] > a").unwrap();
+
+        let topnav_data = html
+            .select(&selector)
+            .filter_map(|c| {
+                Some(GirlGeniusTopnavData {
+                    id: c.value().attr("id")?.to_string(),
+                    href: c.value().attr("href")?.to_string(),
+                })
+            })
+            .collect::<Vec<GirlGeniusTopnavData>>();
+        let topnav = |id: &str| {
+            topnav_data
+                .iter()
+                .find(|d| d.id == id)
+                .map(|d| d.href.clone())
+        };
+
+        let image_selector = Selector::parse("img[alt=\"Comic\"]").unwrap();
+        let images = html
+            .select(&image_selector)
+            .filter_map(|c| c.value().attr("src"))
+            .map(|src| src.to_string())
+            .collect::<Vec<String>>();
+
+        if images.is_empty() {
+            return Err(Error::Scrape {
+                url: url.to_string(),
+                selector: "img[alt=\"Comic\"]".to_string(),
+            });
+        }
+
+        Ok(Scraped {
+            images,
+            previous_url: topnav("topprev"),
+            next_url: topnav("topnext"),
+            first_url: topnav("topfirst"),
+            last_url: topnav("toplast"),
+            metadata: self.metadata(url, html),
+        })
+    }
+}
+
+impl GirlGenius {
+    /// The storyline is whatever's picked in the chapter dropdown. Volumes are either the
+    /// `<optgroup>` the storyline is in, or the storyline itself (`Volume 9`), and every page
+    /// went up on the date it's named after.
+    fn metadata(&self, url: &str, html: &Html) -> PageMetadata {
+        let text = |e: &ElementRef| e.text().collect::<String>().trim().to_string();
+
+        let chapter_selector = Selector::parse("select > option[selected]").unwrap();
+        let selected = html.select(&chapter_selector).find(|c| !text(c).is_empty());
+        let chapter = selected.as_ref().map(text);
+
+        let optgroup = selected
+            .and_then(|option| option.parent())
+            .and_then(|parent| parent.value().as_element())
+            .filter(|parent| parent.name() == "optgroup")
+            .and_then(|parent| parent.attr("label"));
+        let volume = match (optgroup, &chapter) {
+            (Some(group), _) => PageMetadata::number(group),
+            (None, Some(chapter)) if chapter.to_ascii_lowercase().starts_with("vol") => {
+                PageMetadata::number(chapter)
+            }
+            _ => None,
+        };
+
+        let title_selector = Selector::parse("meta[property=\"og:title\"]").unwrap();
+        let title = html
+            .select(&title_selector)
+            .filter_map(|m| m.value().attr("content"))
+            .map(|t| t.trim().to_string())
+            .find(|t| !t.is_empty());
+
+        PageMetadata {
+            volume,
+            chapter,
+            page_in_chapter: None,
+            title,
+            published: self.page_id(url).and_then(|id| PageMetadata::date(&id)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct GirlGeniusTopnavData {
+    id: String,
+    href: String,
+}
+
+fn date_to_gg_string(date: DateTime<Utc>) -> String {
+    format!(
+        "https://www.girlgeniusonline.com/comic.php?date={}",
+        date.format("%Y%m%d")
+    )
+}
@@ -0,0 +1,189 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{comic::Comic, Error};
+
+/// How many pages Back and Forward remember.
+const MAX_STEPS: usize = 100;
+/// How many pages the "recently viewed" list holds.
+const MAX_RECENT: usize = 20;
+
+/// Where the reader has been in one comic, like a browser's history. Only jumps (to a date, a
+/// bookmark, the first or newest page, ...) go on the back stack; reading page by page doesn't.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct History {
+    /// Pages to go back to, oldest first.
+    #[serde(default)]
+    pub back: Vec<String>,
+    /// Pages to go forward to, furthest first.
+    #[serde(default)]
+    pub forward: Vec<String>,
+    /// Every page that's been shown, newest first, without repeats.
+    #[serde(default)]
+    pub recent: Vec<String>,
+}
+
+impl History {
+    /// Where the history is kept: `history.json`, next to the config file.
+    pub fn path(config_path: &str) -> PathBuf {
+        Path::new(config_path)
+            .parent()
+            .unwrap_or_else(|| Path::new("."))
+            .join("history.json")
+    }
+
+    /// `comic`'s history. A missing or unreadable file just means there isn't any yet.
+    pub fn load(config_path: &str, comic: &dyn Comic) -> History {
+        History::read_all(&History::path(config_path))
+            .remove(comic.name())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, config_path: &str, comic: &dyn Comic) -> Result<(), Error> {
+        let path = History::path(config_path);
+        let mut all = History::read_all(&path);
+        all.insert(comic.name().to_string(), self.clone());
+
+        let json = serde_json::to_string(&all)
+            .map_err(|why| Error::Parse(format!("Couldn't serialize the history: {}", why)))?;
+        fs::write(&path, json).map_err(|why| Error::io(path.display().to_string(), why))
+    }
+
+    fn read_all(path: &Path) -> HashMap<String, History> {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|file| serde_json::from_str(&file).ok())
+            .unwrap_or_default()
+    }
+
+    /// Jumped away from `from`, so Back should return to it. Like a browser, that also forgets
+    /// anywhere Forward could have gone.
+    pub fn jumped(&mut self, from: String) {
+        push(&mut self.back, from);
+        self.forward.clear();
+    }
+
+    /// Went back from `from` to the newest page on the back stack.
+    pub fn went_back(&mut self, from: String) {
+        self.back.pop();
+        push(&mut self.forward, from);
+    }
+
+    /// Went forward from `from` to the next page on the forward stack.
+    pub fn went_forward(&mut self, from: String) {
+        self.forward.pop();
+        push(&mut self.back, from);
+    }
+
+    /// Where Back would go.
+    pub fn back_to(&self) -> Option<&String> {
+        self.back.last()
+    }
+
+    /// Where Forward would go.
+    pub fn forward_to(&self) -> Option<&String> {
+        self.forward.last()
+    }
+
+    /// Puts `id` at the top of the recently viewed list.
+    pub fn viewed(&mut self, id: &str) {
+        self.recent.retain(|r| r != id);
+        self.recent.insert(0, id.to_string());
+        self.recent.truncate(MAX_RECENT);
+    }
+}
+
+/// Pushes onto a back/forward stack, skipping repeats and dropping the oldest step if it's full.
+fn push(stack: &mut Vec<String>, id: String) {
+    if stack.last() == Some(&id) {
+        return;
+    }
+    stack.push(id);
+    if stack.len() > MAX_STEPS {
+        stack.remove(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jumping_forgets_where_forward_would_go() {
+        let mut history = History::default();
+        history.jumped("1".to_string());
+        history.jumped("2".to_string());
+        // at 3, back to 2, back to 1
+        history.went_back("3".to_string());
+        history.went_back("2".to_string());
+        assert_eq!(history.forward, ["3", "2"]);
+        assert_eq!(history.forward_to().map(|f| &f[..]), Some("2"));
+
+        history.jumped("1".to_string());
+
+        assert!(history.forward.is_empty());
+        assert_eq!(history.forward_to(), None);
+        assert_eq!(history.back_to().map(|b| &b[..]), Some("1"));
+    }
+
+    #[test]
+    fn back_and_forward_move_pages_between_the_stacks() {
+        let mut history = History::default();
+        history.jumped("1".to_string());
+        history.went_back("2".to_string());
+        assert_eq!(history.back_to(), None);
+
+        history.went_forward("1".to_string());
+
+        assert_eq!(history.back, ["1"]);
+        assert!(history.forward.is_empty());
+    }
+
+    #[test]
+    fn only_the_newest_steps_are_kept() {
+        let mut history = History::default();
+        for id in 0..MAX_STEPS + 5 {
+            history.jumped(id.to_string());
+        }
+
+        assert_eq!(history.back.len(), MAX_STEPS);
+        assert_eq!(history.back[0], "5");
+        assert_eq!(history.back_to().map(|b| &b[..]), Some("104"));
+    }
+
+    #[test]
+    fn jumping_from_the_same_page_twice_is_one_step() {
+        let mut history = History::default();
+        history.jumped("1".to_string());
+        history.jumped("1".to_string());
+
+        assert_eq!(history.back, ["1"]);
+    }
+
+    #[test]
+    fn recent_pages_are_newest_first_without_repeats() {
+        let mut history = History::default();
+        for id in ["1", "2", "3", "1"] {
+            history.viewed(id);
+        }
+
+        assert_eq!(history.recent, ["1", "3", "2"]);
+    }
+
+    #[test]
+    fn only_the_newest_recent_pages_are_kept() {
+        let mut history = History::default();
+        for id in 0..MAX_RECENT + 5 {
+            history.viewed(&id.to_string());
+        }
+
+        assert_eq!(history.recent.len(), MAX_RECENT);
+        assert_eq!(history.recent[0], "24");
+        assert_eq!(history.recent[MAX_RECENT - 1], "5");
+    }
+}
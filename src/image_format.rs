@@ -0,0 +1,177 @@
+use std::{fs, io::Read};
+
+use crate::Error;
+
+/// The image formats we'll cache. Anything else a server sends back (usually an HTML error
+/// page) is rejected rather than saved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+    Gif,
+    Webp,
+}
+
+impl ImageFormat {
+    pub const ALL: [ImageFormat; 4] = [
+        ImageFormat::Jpeg,
+        ImageFormat::Png,
+        ImageFormat::Gif,
+        ImageFormat::Webp,
+    ];
+
+    /// The extension cached files get, without the dot.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Png => "png",
+            ImageFormat::Gif => "gif",
+            ImageFormat::Webp => "webp",
+        }
+    }
+
+    /// The MIME type, like `image/png`.
+    pub fn media_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Png => "image/png",
+            ImageFormat::Gif => "image/gif",
+            ImageFormat::Webp => "image/webp",
+        }
+    }
+
+    /// Goes by a cached file's extension, which is always the real format.
+    pub fn from_extension(extension: &str) -> Option<ImageFormat> {
+        match &extension.to_ascii_lowercase()[..] {
+            "jpg" | "jpeg" => Some(ImageFormat::Jpeg),
+            "png" => Some(ImageFormat::Png),
+            "gif" => Some(ImageFormat::Gif),
+            "webp" => Some(ImageFormat::Webp),
+            _ => None,
+        }
+    }
+
+    /// Recognises an image by its first few bytes.
+    pub fn from_magic(bytes: &[u8]) -> Option<ImageFormat> {
+        match bytes {
+            [0xFF, 0xD8, 0xFF, ..] => Some(ImageFormat::Jpeg),
+            [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => Some(ImageFormat::Png),
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some(ImageFormat::Gif),
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => {
+                Some(ImageFormat::Webp)
+            }
+            _ => None,
+        }
+    }
+
+    /// Recognises the image in the file at `path` by its first few bytes.
+    pub fn of_file(path: &str) -> Result<Option<ImageFormat>, Error> {
+        let mut magic = [0; 12];
+        let read = fs::File::open(path)
+            .and_then(|mut file| file.read(&mut magic))
+            .map_err(|why| Error::io(path, why))?;
+
+        Ok(ImageFormat::from_magic(&magic[..read]))
+    }
+
+    /// Reads a `Content-Type` header, like `image/png` or `image/jpeg; charset=binary`.
+    pub fn from_content_type(content_type: &str) -> Option<ImageFormat> {
+        let mime = content_type.split(';').next()?.trim().to_ascii_lowercase();
+
+        match &mime[..] {
+            "image/jpeg" | "image/jpg" | "image/pjpeg" => Some(ImageFormat::Jpeg),
+            "image/png" => Some(ImageFormat::Png),
+            "image/gif" => Some(ImageFormat::Gif),
+            "image/webp" => Some(ImageFormat::Webp),
+            _ => None,
+        }
+    }
+
+    /// Works out what `url` sent back from its first few bytes. The `Content-Type` is never
+    /// enough on its own: an empty body or an HTML error page can come labelled `image/*`.
+    /// When the header names a different image format than the bytes, the bytes win, since
+    /// servers often get it wrong.
+    pub fn detect(
+        url: &str,
+        content_type: Option<&str>,
+        bytes: &[u8],
+    ) -> Result<ImageFormat, Error> {
+        let format = ImageFormat::from_magic(bytes).ok_or_else(|| Error::NotAnImage {
+            url: url.to_string(),
+            content_type: content_type.map(|c| c.to_string()),
+        })?;
+
+        if let Some(claimed) = content_type.and_then(ImageFormat::from_content_type) {
+            if claimed != format {
+                eprintln!(
+                    "`{}` says it's {}, but it's {}",
+                    url,
+                    claimed.media_type(),
+                    format.media_type()
+                );
+            }
+        }
+
+        Ok(format)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn images_are_recognised_by_their_first_bytes() {
+        let cases: [(&[u8], Option<ImageFormat>); 12] = [
+            (b"\xFF\xD8\xFF\xE0\x00\x10JFIF", Some(ImageFormat::Jpeg)),
+            (
+                b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR",
+                Some(ImageFormat::Png),
+            ),
+            (b"GIF87a\x01\x00\x01\x00", Some(ImageFormat::Gif)),
+            (b"GIF89a\x01\x00\x01\x00", Some(ImageFormat::Gif)),
+            (b"RIFF\x24\x00\x00\x00WEBPVP8 ", Some(ImageFormat::Webp)),
+            // cut off before the end of the signature
+            (b"", None),
+            (b"\xFF\xD8", None),
+            (b"\x89PNG\r\n", None),
+            (b"GIF8", None),
+            (b"RIFF\x24\x00\x00\x00WEB", None),
+            // a RIFF file that isn't a WebP, like a WAV
+            (b"RIFF\x24\x00\x00\x00WAVEfmt ", None),
+            (b"<!DOCTYPE html>", None),
+        ];
+
+        for (bytes, format) in cases {
+            assert_eq!(ImageFormat::from_magic(bytes), format, "{:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn an_error_page_labelled_as_an_image_is_refused() {
+        let page = b"<!DOCTYPE html><html><body>503 Service Unavailable</body></html>";
+
+        match ImageFormat::detect("https://example.com/1.jpg", Some("image/jpeg"), page) {
+            Err(Error::NotAnImage { url, content_type }) => {
+                assert_eq!(url, "https://example.com/1.jpg");
+                assert_eq!(content_type.as_deref(), Some("image/jpeg"));
+            }
+            other => panic!("expected NotAnImage, got {:?}", other),
+        }
+        assert!(ImageFormat::detect("https://example.com/1.jpg", Some("image/jpeg"), b"").is_err());
+    }
+
+    #[test]
+    fn the_bytes_win_over_the_content_type() {
+        let png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR";
+
+        assert_eq!(
+            ImageFormat::detect("https://example.com/1.jpg", Some("image/jpeg"), png).unwrap(),
+            ImageFormat::Png
+        );
+        assert_eq!(
+            ImageFormat::detect("https://example.com/1", None, png).unwrap(),
+            ImageFormat::Png
+        );
+    }
+}
@@ -1,10 +1,31 @@
+pub mod cache;
+pub mod comic;
+pub mod definition;
+pub mod error;
+pub mod export;
+pub mod girl_genius;
+pub mod history;
+pub mod image_format;
+pub mod server;
 pub mod structs;
+pub mod sync;
 pub mod ui;
+pub mod verify;
+
+pub use error::Error;
 
 use chrono::{DateTime, TimeZone, Utc};
+use comic::Comic;
+use image_format::ImageFormat;
 use regex::Regex;
-use std::{env, process};
-use structs::{ConfigFile, GirlGeniusPage, GggCacheData};
+use std::{
+    cmp::Ordering,
+    env,
+    future::Future,
+    sync::{Arc, LazyLock, Mutex},
+};
+use structs::{ComicPage, Config, ConfigFile, GggCacheData};
+use tokio::task::JoinHandle;
 
 pub fn home_dir() -> String {
     match std::env::consts::OS {
@@ -14,66 +35,387 @@ pub fn home_dir() -> String {
     }
 }
 
-pub fn parse_gg_string_for_date(string: String) -> DateTime<Utc> {
-    let regex = Regex::new(r"(\d{4})(\d{2})(\d{2})").expect("Couldn't compile regex");
+/// The date in a date id (`YYYYMMDD`, like Girl Genius's), at noon UTC. The id can be part of
+/// something bigger, like a URL.
+pub fn parse_date_id(id: &str) -> Result<DateTime<Utc>, Error> {
+    static DATE: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"(\d{4})(\d{2})(\d{2})").expect("Couldn't compile regex"));
+
+    let captures = match DATE.captures(id) {
+        Some(c) => c,
+        None => {
+            return Err(Error::Parse(format!(
+                "Couldn't find a `YYYYMMDD` date in `{}`",
+                id
+            )))
+        }
+    };
+
+    Utc.with_ymd_and_hms(
+        captures[1].parse::<i32>().unwrap(),
+        captures[2].parse::<u32>().unwrap(),
+        captures[3].parse::<u32>().unwrap(),
+        12,
+        0,
+        0,
+    )
+    .single()
+    .ok_or_else(|| Error::Parse(format!("`{}` isn't a real date", &captures[0])))
+}
+
+/// The date in a page id as `YYYY-MM-DD`, for comics whose ids are dates.
+pub fn id_date(id: &str) -> Option<String> {
+    parse_date_id(id)
+        .ok()
+        .map(|date| date.format("%Y-%m-%d").to_string())
+}
+
+/// A page that's on disk, and where it came from.
+#[derive(Debug, Clone)]
+pub struct Page {
+    pub data: GggCacheData,
+    /// Where the image is.
+    pub path: String,
+    /// Whether it was already cached, rather than just downloaded.
+    pub from_cache: bool,
+}
+
+impl Page {
+    /// What kind of image it is. Cached images are always named after their real format.
+    pub fn format(&self) -> ImageFormat {
+        std::path::Path::new(&self.path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .and_then(ImageFormat::from_extension)
+            .unwrap_or(ImageFormat::Jpeg)
+    }
+}
+
+/// Orders page ids: numerically if they're both numbers (which dates like `20021104` are too),
+/// and as plain strings otherwise.
+pub fn compare_ids(a: &str, b: &str) -> Ordering {
+    match (a.parse::<u64>(), b.parse::<u64>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        _ => a.cmp(b),
+    }
+}
+
+/// Gets a page from the cache if it's there, downloading (and caching) it if it isn't. When
+/// `offline` is set, only the cache is used.
+pub async fn page(
+    comic: &dyn Comic,
+    id: String,
+    cache_dir: &String,
+    offline: bool,
+) -> Result<Page, Error> {
+    if let Some(data) = GggCacheData::find(cache_dir, &id) {
+        if data.is_cached(cache_dir) {
+            data.touch(cache_dir);
+            return Ok(Page {
+                path: data.path(cache_dir),
+                data,
+                from_cache: true,
+            });
+        }
+    }
+
+    if offline {
+        return Err(Error::NotCached(id));
+    }
+
+    let data = ComicPage::new(comic, &id).await?.save(cache_dir).await?;
+
+    Ok(Page {
+        path: data.path(cache_dir),
+        data,
+        from_cache: false,
+    })
+}
+
+/// Keeps the cache within `config`'s limits after `page` was downloaded (see
+/// `cache::prune_after_save`). Pages that were already cached don't change anything.
+pub(crate) fn pruned(comic: &dyn Comic, config: &Config, cache_dir: &String, page: &Page) {
+    if !page.from_cache {
+        cache::prune_after_save(comic, config, cache_dir, &[&page.data]);
+    }
+}
+
+/// Fetches a page from the site just to learn its links, recording them in the cache index.
+pub(crate) async fn refresh_links(
+    comic: &dyn Comic,
+    id: String,
+    cache_dir: &String,
+) -> Result<GggCacheData, Error> {
+    ComicPage::new(comic, &id).await?.save(cache_dir).await
+}
+
+/// The first and newest pages of the archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveBounds {
+    pub first: String,
+    pub latest: String,
+}
+
+/// Works out where the archive starts and ends. Offline, that's wherever the cache starts and
+/// ends.
+pub async fn bounds(
+    comic: &dyn Comic,
+    cache_dir: &str,
+    offline: bool,
+) -> Result<ArchiveBounds, Error> {
+    if offline {
+        let cached = GggCacheData::read(cache_dir.to_string());
+        let first = cached
+            .iter()
+            .map(|p| &p.id)
+            .min_by(|a, b| compare_ids(a, b));
+        let latest = cached
+            .iter()
+            .map(|p| &p.id)
+            .max_by(|a, b| compare_ids(a, b));
+
+        return match (first, latest) {
+            (Some(first), Some(latest)) => Ok(ArchiveBounds {
+                first: first.clone(),
+                latest: latest.clone(),
+            }),
+            _ => Err(Error::NotCached("Every page".to_string())),
+        };
+    }
+
+    Ok(ArchiveBounds {
+        first: comic.first_page().to_string(),
+        latest: latest_page(comic).await?,
+    })
+}
+
+/// Asks the site for the id of its newest page.
+pub async fn latest_page(comic: &dyn Comic) -> Result<String, Error> {
+    let front = ComicPage::latest(comic).await?;
 
-    let captures_maybe = regex.captures(&string[..]);
+    match (front.id, front.last, front.previous) {
+        (Some(id), _, _) => Ok(id),
+        (None, Some(last), _) => Ok(last),
+        // the front page doesn't say which page it is, but the page before it knows
+        (None, None, Some(prev)) => ComicPage::new(comic, &prev).await?.next.ok_or_else(|| {
+            Error::Parse("The newest page's previous page has no next link".to_string())
+        }),
+        (None, None, None) => Err(Error::Parse(format!(
+            "Couldn't work out which page `{}` is",
+            comic.latest_url()
+        ))),
+    }
+}
 
-    if let None = captures_maybe {
-        eprintln!("Couldn't parse date info from a GG date string");
-        process::exit(1);
+pub async fn next(
+    comic: &dyn Comic,
+    conf: ConfigFile,
+    cache_dir: &String,
+    offline: bool,
+) -> Result<Option<Page>, Error> {
+    let current = conf.read().position(comic);
+    let next_id = match GggCacheData::find(cache_dir, &current).and_then(|p| p.next) {
+        Some(id) => Some(id),
+        None if offline => {
+            return Err(Error::NotCached(format!("The page after {}", current)));
+        }
+        // either this page isn't in the index, or it was the newest page when it was cached
+        None => refresh_links(comic, current, cache_dir).await?.next,
     };
 
-    let captures = regex.captures(&string[..]).expect("literally how");
+    match next_id {
+        Some(id) => {
+            let next_page = page(comic, id, cache_dir, offline).await?;
+            pruned(comic, &conf.read(), cache_dir, &next_page);
+            conf.update_position(comic, next_page.data.id.clone());
 
-    Utc.ymd(
-        captures[1].to_string().parse::<i32>().unwrap(),
-        captures[2].to_string().parse::<u32>().unwrap(),
-        captures[3].to_string().parse::<u32>().unwrap(),
-    )
-    .and_hms(12, 0, 0)
+            Ok(Some(next_page))
+        }
+        None => Ok(None),
+    }
 }
 
-pub fn date_to_gg_string(date: DateTime<Utc>) -> String {
-    format!(
-        "https://www.girlgeniusonline.com/comic.php?date={}",
-        date.format("%Y%m%d").to_string()
-    )
+pub async fn previous(
+    comic: &dyn Comic,
+    conf: ConfigFile,
+    cache_dir: &String,
+    offline: bool,
+) -> Result<Option<Page>, Error> {
+    let current = conf.read().position(comic);
+    if current == comic.first_page() {
+        return Ok(None);
+    }
+
+    let prev_id = match GggCacheData::find(cache_dir, &current).and_then(|p| p.previous) {
+        Some(id) => Some(id),
+        None if offline => {
+            return Err(Error::NotCached(format!("The page before {}", current)));
+        }
+        None => refresh_links(comic, current, cache_dir).await?.previous,
+    };
+
+    match prev_id {
+        Some(id) => {
+            let prev_page = page(comic, id, cache_dir, offline).await?;
+            pruned(comic, &conf.read(), cache_dir, &prev_page);
+            conf.update_position(comic, prev_page.data.id.clone());
+
+            Ok(Some(prev_page))
+        }
+        None => Ok(None),
+    }
 }
 
-pub async fn next(conf: ConfigFile, cache_dir: &String) -> Option<(GirlGeniusPage, String)> {
-    let current_page = GirlGeniusPage::new(parse_gg_string_for_date(conf.read().latest_page)).await;
-    if let Some(next) = current_page.next_url {
-        // println!("{}", next);
-        let next_page = GirlGeniusPage::new(parse_gg_string_for_date(next)).await;
-        let next_page_date = parse_gg_string_for_date(next_page.current_url.clone());
-        conf.update_latest_page(next_page_date.format("%Y%m%d").to_string());
+/// Walks forward from `from`, making sure the next `config.prefetch_ahead` pages are cached. Pages that are
+/// already cached (and linked in the index) don't cost a request, so it's cheap to call this
+/// again every time the reader moves.
+pub async fn prefetch(
+    comic: &dyn Comic,
+    config: &Config,
+    from: String,
+    cache_dir: String,
+) -> Result<(), Error> {
+    let mut current = from;
 
-        let filepath = next_page.save(cache_dir).await;
+    for _ in 0..config.prefetch_ahead {
+        let next_id = match GggCacheData::find(&cache_dir, &current).and_then(|p| p.next) {
+            Some(id) => id,
+            None => match refresh_links(comic, current, &cache_dir).await?.next {
+                Some(id) => id,
+                // caught up to the newest page
+                None => break,
+            },
+        };
 
-        Some((next_page, filepath))
-    } else {
-        None
+        let page = page(comic, next_id.clone(), &cache_dir, false).await?;
+        pruned(comic, config, &cache_dir, &page);
+        current = next_id;
     }
+
+    Ok(())
 }
 
-pub async fn previous(conf: ConfigFile, cache_dir: &String) -> Option<(GirlGeniusPage, String)> {
-    if conf.read().latest_page == String::from("20021104") {
-        None
-    } else {
-        let current_page =
-            GirlGeniusPage::new(parse_gg_string_for_date(cp)).await;
-        if let Some(prev) = current_page.previous_url {
-            // println!("{}", next);
-            let prev_page = GirlGeniusPage::new(parse_gg_string_for_date(prev)).await;
-            let prev_page_date = parse_gg_string_for_date(prev_page.current_url.clone());
-            conf.update_latest_page(prev_page_date.format("%Y%m%d").to_string());
+/// A `prefetch` running in the background, for the app and the server.
+#[derive(Debug, Default)]
+pub struct Prefetcher {
+    running: Mutex<Option<JoinHandle<()>>>,
+}
 
-            let filepath = prev_page.save(cache_dir).await;
+impl Prefetcher {
+    /// Starts caching the `config.prefetch_ahead` pages after `from`, replacing whatever was being prefetched
+    /// before (the reader has moved, so it's probably not needed anymore). Offline, it just
+    /// stops.
+    pub fn restart(
+        &self,
+        comic: Arc<dyn Comic>,
+        config: Config,
+        from: String,
+        cache_dir: String,
+        offline: bool,
+    ) {
+        let mut running = self.running.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(handle) = running.take() {
+            handle.abort();
+        }
+        if offline {
+            return;
+        }
+
+        *running = Some(tokio::spawn(async move {
+            if let Err(why) = prefetch(comic.as_ref(), &config, from, cache_dir).await {
+                eprintln!("Couldn't prefetch the next pages: {}", why);
+            }
+        }));
+    }
+}
+
+/// Runs `attempt` online (unless `offline`), then again from the cache if the site couldn't be
+/// reached.
+pub async fn or_from_cache<T, F, Fut>(offline: bool, attempt: F) -> Result<T, Error>
+where
+    F: Fn(bool) -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    match attempt(offline).await {
+        Err(Error::Network(_)) if !offline => attempt(true).await,
+        result => result,
+    }
+}
+
+/// Jumps straight to the page for `id` (anything `Comic::parse_id` understands), or the nearest
+/// real page if there's no page with that id.
+pub async fn goto(
+    comic: &dyn Comic,
+    conf: ConfigFile,
+    cache_dir: &String,
+    id: String,
+    offline: bool,
+) -> Result<Page, Error> {
+    let resolved = resolve_page(comic, id, cache_dir, offline).await?;
+    let page = page(comic, resolved, cache_dir, offline).await?;
+    pruned(comic, &conf.read(), cache_dir, &page);
+    conf.update_position(comic, page.data.id.clone());
+
+    Ok(page)
+}
+
+/// Turns any id into the id of a page that actually exists.
+///
+/// Ids we've already cached are taken as-is. Otherwise, we ask the site (which may redirect
+/// to the real page), then double check with the previous page's next link, since that always
+/// points at a real page. If there's no page at all, we step back one id at a time, up to
+/// `Comic::max_gap` times.
+/// Offline, the closest cached page is used instead.
+pub async fn resolve_page(
+    comic: &dyn Comic,
+    id: String,
+    cache_dir: &str,
+    offline: bool,
+) -> Result<String, Error> {
+    let id = comic.parse_id(&id)?;
+
+    if GggCacheData::find(cache_dir, &id).is_some() {
+        return Ok(id);
+    }
+
+    if offline {
+        let cached = GggCacheData::read(cache_dir.to_string());
+        let before = cached
+            .iter()
+            .filter(|p| compare_ids(&p.id, &id) != Ordering::Greater)
+            .max_by(|a, b| compare_ids(&a.id, &b.id));
+        let after = cached
+            .iter()
+            .filter(|p| compare_ids(&p.id, &id) == Ordering::Greater)
+            .min_by(|a, b| compare_ids(&a.id, &b.id));
 
-            Some((prev_page, filepath))
-        } else {
-            None
+        return before
+            .or(after)
+            .map(|p| p.id.clone())
+            .ok_or(Error::NotCached(format!("Anything near {}", id)));
+    }
+
+    let mut candidate = id.clone();
+    for _ in 0..comic.max_gap() {
+        match ComicPage::new(comic, &candidate).await {
+            Ok(found) => {
+                if let Some(prev) = &found.previous {
+                    if let Some(real) = ComicPage::new(comic, prev).await?.next {
+                        return Ok(real);
+                    }
+                }
+
+                return found.id().cloned();
+            }
+            Err(Error::Scrape { .. }) | Err(Error::Status { .. }) => {
+                match comic.step_back(&candidate) {
+                    Some(earlier) => candidate = earlier,
+                    None => break,
+                }
+            }
+            Err(why) => return Err(why),
         }
     }
+
+    Err(Error::Parse(format!("Couldn't find a page near {}", id)))
 }
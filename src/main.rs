@@ -1,12 +1,25 @@
-use clap::{arg, ArgAction, Command};
+use clap::{arg, value_parser, ArgAction, ArgMatches, Command};
 use ggg::{
-    home_dir,
-    structs::ConfigFile,
+    cache::{prune, stats, Limits},
+    comic::{find_comic, Comic},
+    export::{
+        cbz::export_cbz,
+        epub::export_epub,
+        html::export_html,
+        pdf::{export_pdf, Paper, PdfOptions},
+        ExportPage, Range,
+    },
+    girl_genius::GirlGenius,
+    home_dir, id_date, resolve_page,
+    server::{serve, Server},
+    structs::{Config, ConfigFile, GggCacheData},
+    sync::sync,
     ui::GggUi,
-    structs::GggCacheData
+    verify::{repair, verify},
 };
-use iced::{Settings, Application};
-use std::{fs, path::Path, process};
+use iced::{Application, Settings};
+use std::{fs, io::Write, net::SocketAddr, path::Path, process, sync::Arc};
+use yansi::Color;
 
 fn cli() -> Command {
     Command::new("ggg")
@@ -23,7 +36,140 @@ fn cli() -> Command {
             arg!(-d --cache_dir "The cache directory to use. Defaults to ~/.cache/ggg")
                 .action(ArgAction::Set),
         )
+        .arg(
+            arg!(--offline "Only show pages that are already in the cache")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(-g --goto <YYYYMMDD> "Open the comic at this date (or the nearest page to it)")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            arg!(--comic <NAME> "The comic to read: girl-genius, or the name of a definition in ~/.config/ggg/comics")
+                .default_value("girl-genius"),
+        )
         .arg(arg!(-s --string "string").action(ArgAction::Set))
+        .subcommand(
+            Command::new("sync")
+                .about("Download every page of the comic into the cache, picking up where the last sync stopped")
+                .arg(
+                    arg!(-j --jobs <JOBS> "How many images to download at once")
+                        .value_parser(value_parser!(usize))
+                        .default_value("4"),
+                ),
+        )
+        .subcommand(
+            Command::new("bookmark")
+                .about("Keep more than one place in the comic")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("add")
+                        .about("Bookmark a page (by default, the one you're on)")
+                        .arg(arg!(<NAME> "What to call the bookmark"))
+                        .arg(arg!([PAGE] "The page to bookmark, like 20090318")),
+                )
+                .subcommand(Command::new("list").about("Show this comic's bookmarks"))
+                .subcommand(
+                    Command::new("go")
+                        .about("Read from a bookmark, so navigating moves it instead of your reading position")
+                        .arg(arg!([NAME] "The bookmark to read from. Leave it out to go back to your reading position")),
+                )
+                .subcommand(
+                    Command::new("rename")
+                        .about("Rename a bookmark")
+                        .arg(arg!(<NAME> "The bookmark to rename"))
+                        .arg(arg!(<NEW_NAME> "What to call it now")),
+                )
+                .subcommand(
+                    Command::new("rm")
+                        .about("Delete a bookmark")
+                        .arg(arg!(<NAME> "The bookmark to delete")),
+                ),
+        )
+        .subcommand(
+            Command::new("cache")
+                .about("Look after the cache")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("verify")
+                        .about("Decode every cached image, and check the cache index matches what's on disk")
+                        .arg(
+                            arg!(--repair "Download corrupt and missing images again, index unindexed ones, and delete orphaned files")
+                                .action(ArgAction::SetTrue),
+                        )
+                        .arg(
+                            arg!(-j --jobs <JOBS> "How many images to check at once")
+                                .value_parser(value_parser!(usize))
+                                .default_value("4"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("prune")
+                        .about("Delete cached images to fit the limits in the config file")
+                        .arg(
+                            arg!(--max_bytes <BYTES> "Use this size limit instead of max_cache_bytes")
+                                .value_parser(value_parser!(u64)),
+                        )
+                        .arg(
+                            arg!(--keep_within <PAGES> "Use this instead of keep_within_pages_of_bookmark")
+                                .value_parser(value_parser!(usize)),
+                        ),
+                )
+                .subcommand(
+                    Command::new("stats").about("Show how many pages are cached, and how much space they take"),
+                ),
+        )
+        .subcommand(
+            Command::new("serve")
+                .about("Serve a web reader, the cached images and a JSON API, for reading on another device or scripting")
+                .arg(
+                    arg!(-l --listen <ADDRESS> "Where to listen. Use 0.0.0.0:8080 to read from other devices on your network")
+                        .value_parser(value_parser!(SocketAddr))
+                        .default_value("127.0.0.1:8080"),
+                ),
+        )
+        .subcommand(
+            Command::new("export")
+                .about("Save a range of pages for other readers. Pages that aren't cached are downloaded")
+                .subcommand_required(true)
+                .subcommand(export_command(
+                    "cbz",
+                    "A comic book archive with ComicInfo.xml, for comic reader apps",
+                ))
+                .subcommand(export_command(
+                    "epub",
+                    "A fixed-layout EPUB 3, one page per image, for e-book readers",
+                ))
+                .subcommand(export_command(
+                    "html",
+                    "A static website in the --output directory, for reading in any browser",
+                ))
+                .subcommand(
+                    export_command(
+                        "pdf",
+                        "A PDF, one page per image, with a chapter outline, for printing",
+                    )
+                    .arg(
+                        arg!(--captions "Print each page's date and chapter under it")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        arg!(--paper <SIZE> "The paper size")
+                            .value_parser(["a4", "letter"])
+                            .default_value("a4"),
+                    ),
+                ),
+        )
+}
+
+/// An export format's subcommand, with the arguments every format shares.
+fn export_command(name: &'static str, about: &'static str) -> Command {
+    Command::new(name)
+        .about(about)
+        .arg(arg!(--from <PAGE> "The first page to export, like 20090101. Defaults to the start of the chapter, or the first page"))
+        .arg(arg!(--to <PAGE> "The last page to export. Defaults to the end of the chapter, or the newest page"))
+        .arg(arg!(--chapter <NAME> "Only export pages from this storyline, as the site names it (like \"Volume 9\")"))
+        .arg(arg!(-o --output <PATH> "Where to save it").required(true))
 }
 
 #[tokio::main]
@@ -42,20 +188,113 @@ async fn main() {
         .get_one::<String>("cache_dir")
         .unwrap_or(&config.cache_dir);
 
-    std::env::set_var("ggg_config_path", config_file_path.clone());
-    std::env::set_var("ggg_cache_path", cache_dir_path);
+    let definitions_dir = Path::new(config_file_path)
+        .parent()
+        .unwrap_or_else(|| Path::new("."))
+        .join("comics");
+    let comic: Arc<dyn Comic> = match find_comic(
+        matches.get_one::<String>("comic").unwrap(),
+        &definitions_dir,
+    ) {
+        Ok(comic) => comic,
+        Err(why) => {
+            eprintln!("{}", why);
+            process::exit(1);
+        }
+    };
 
     check_cache_dir(cache_dir_path);
+    // every comic gets its own corner of the cache
+    let comic_cache_dir = comic_cache_dir(cache_dir_path, comic.as_ref());
+    match GggCacheData::migrate_formats(comic.as_ref(), &comic_cache_dir) {
+        Ok(0) => {}
+        Ok(fixed) => println!("Fixed the format of {} cached images", fixed),
+        Err(why) => eprintln!("Couldn't check the formats of the cached images: {}", why),
+    }
 
-    // previous(ConfigFile{path:std::env::var("ggg_config_path").unwrap()}, &std::env::var("ggg_cache_path").unwrap()).await;
+    std::env::set_var("ggg_config_path", config_file_path.clone());
+    std::env::set_var("ggg_cache_path", &comic_cache_dir);
+    if matches.get_flag("offline") {
+        std::env::set_var("ggg_offline", "1");
+    }
 
-    println!("{:?}", GggCacheData::read(cache_dir_path.clone().to_string()));
+    if let Some(goto) = matches.get_one::<String>("goto") {
+        match comic.parse_id(goto) {
+            Ok(id) => std::env::set_var("ggg_goto", id),
+            Err(why) => {
+                eprintln!("{}", why);
+                process::exit(1);
+            }
+        }
+    }
+
+    // previous(ConfigFile{path:std::env::var("ggg_config_path").unwrap()}, &std::env::var("ggg_cache_path").unwrap()).await;
 
-    GggUi::run(Settings::default()).unwrap();
+    match matches.subcommand() {
+        Some(("sync", sub_matches)) => {
+            run_sync(comic.as_ref(), &config, sub_matches, &comic_cache_dir).await
+        }
+        Some(("cache", sub_matches)) => match sub_matches.subcommand() {
+            Some(("verify", verify_matches)) => {
+                run_verify(comic.as_ref(), verify_matches, &comic_cache_dir).await
+            }
+            Some(("prune", prune_matches)) => {
+                let conf = ConfigFile {
+                    path: config_file_path.clone(),
+                };
+                run_prune(comic.as_ref(), prune_matches, conf, &comic_cache_dir)
+            }
+            Some(("stats", _)) => run_stats(&comic_cache_dir),
+            _ => unreachable!("clap requires a cache subcommand"),
+        },
+        Some(("export", sub_matches)) => {
+            run_export(
+                comic.as_ref(),
+                sub_matches,
+                &comic_cache_dir,
+                matches.get_flag("offline"),
+            )
+            .await
+        }
+        Some(("serve", sub_matches)) => {
+            let server = Server::new(
+                comic.clone(),
+                ConfigFile {
+                    path: config_file_path.clone(),
+                },
+                comic_cache_dir.clone(),
+                matches.get_flag("offline"),
+            );
+            let listen = *sub_matches
+                .get_one::<SocketAddr>("listen")
+                .expect("listen has a default");
+            if let Err(why) = serve(server, listen).await {
+                eprintln!("{} {}", Color::Red.paint("Error:"), why);
+                process::exit(1);
+            }
+        }
+        Some(("bookmark", sub_matches)) => {
+            let conf = ConfigFile {
+                path: config_file_path.clone(),
+            };
+            let open = run_bookmark(
+                comic.as_ref(),
+                sub_matches,
+                conf,
+                &comic_cache_dir,
+                matches.get_flag("offline"),
+            )
+            .await;
+            if open {
+                GggUi::run(Settings::with_flags(comic)).unwrap()
+            }
+        }
+        _ => GggUi::run(Settings::with_flags(comic)).unwrap(),
+    }
 
     // process::exit(1);
 
-    // let filepath = GirlGeniusPage::new(parse_gg_string_for_date(config.latest_page))
+    // let filepath = GirlGeniusPage::new(parse_date_id(&config.latest_page))
     //     .await
     //     .save(cache_dir_path)
     //     .await;
@@ -141,6 +380,334 @@ async fn main() {
     // }
 }
 
+/// Runs a `ggg bookmark` command. Returns whether to open the reader afterwards.
+async fn run_bookmark(
+    comic: &dyn Comic,
+    matches: &ArgMatches,
+    conf: ConfigFile,
+    cache_dir: &str,
+    offline: bool,
+) -> bool {
+    let done = match matches.subcommand() {
+        Some(("add", sub)) => {
+            let name = sub.get_one::<String>("NAME").unwrap();
+            let page = match sub.get_one::<String>("PAGE") {
+                Some(page) => resolve_page(comic, page.clone(), cache_dir, offline).await,
+                None => Ok(conf.read().position(comic)),
+            };
+
+            page.and_then(|page| {
+                conf.add_bookmark(comic, name, page.clone())?;
+                println!("{} {} at {}", Color::Green.paint("Bookmarked"), name, page);
+                Ok(false)
+            })
+        }
+        Some(("list", _)) => {
+            let config = conf.read();
+            let active = config.active_bookmark(comic).map(|b| b.name.clone());
+            let marker = |is_active: bool| if is_active { "*" } else { " " };
+
+            println!(
+                "{} {:<20} {}",
+                marker(active.is_none()),
+                "(reading position)",
+                config.reading_position(comic)
+            );
+            for bookmark in config.bookmarks_for(comic) {
+                println!(
+                    "{} {:<20} {}",
+                    marker(active.as_ref() == Some(&bookmark.name)),
+                    bookmark.name,
+                    bookmark.page
+                );
+            }
+            Ok(false)
+        }
+        Some(("go", sub)) => {
+            let name = sub.get_one::<String>("NAME").map(|n| &n[..]);
+            conf.activate_bookmark(comic, name).map(|page| {
+                println!(
+                    "{} {} at {}",
+                    Color::Green.paint("Reading from"),
+                    name.unwrap_or("your reading position"),
+                    page
+                );
+                true
+            })
+        }
+        Some(("rename", sub)) => {
+            let name = sub.get_one::<String>("NAME").unwrap();
+            let new_name = sub.get_one::<String>("NEW_NAME").unwrap();
+            conf.rename_bookmark(comic, name, new_name).map(|_| false)
+        }
+        Some(("rm", sub)) => {
+            let name = sub.get_one::<String>("NAME").unwrap();
+            conf.remove_bookmark(comic, name).map(|_| false)
+        }
+        _ => unreachable!("clap requires a bookmark subcommand"),
+    };
+
+    match done {
+        Ok(open) => open,
+        Err(why) => {
+            eprintln!("{} {}", Color::Red.paint("Error:"), why);
+            process::exit(1);
+        }
+    }
+}
+
+async fn run_export(comic: &dyn Comic, matches: &ArgMatches, cache_dir: &String, offline: bool) {
+    let (format, sub) = matches
+        .subcommand()
+        .expect("clap requires an export subcommand");
+    let parse = |arg: &str| match sub.get_one::<String>(arg) {
+        Some(id) => match comic.parse_id(id) {
+            Ok(id) => Some(id),
+            Err(why) => {
+                eprintln!("--{}: {}", arg, why);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let range = Range {
+        from: parse("from"),
+        to: parse("to"),
+        chapter: sub.get_one::<String>("chapter").cloned(),
+    };
+    let out = Path::new(sub.get_one::<String>("output").expect("output is required"));
+
+    let progress = |page: &ExportPage| {
+        print!("\r{} {}", Color::Cyan.paint("Exporting"), page.heading());
+        let _ = std::io::stdout().flush();
+    };
+    let exported = match format {
+        "cbz" => export_cbz(comic, cache_dir, &range, offline, out, progress).await,
+        "epub" => export_epub(comic, cache_dir, &range, offline, out, progress).await,
+        "html" => export_html(comic, cache_dir, &range, offline, out, progress).await,
+        "pdf" => {
+            let options = PdfOptions {
+                paper: match sub.get_one::<String>("paper").map(|p| &p[..]) {
+                    Some("letter") => Paper::Letter,
+                    _ => Paper::A4,
+                },
+                captions: sub.get_flag("captions"),
+            };
+            export_pdf(comic, cache_dir, &range, offline, options, out, progress).await
+        }
+        _ => unreachable!("clap only allows known formats"),
+    };
+    println!();
+
+    match exported {
+        Ok(pages) => println!(
+            "{} {} pages to {}",
+            Color::Green.paint("Exported"),
+            pages,
+            out.display()
+        ),
+        Err(why) => {
+            eprintln!("{} {}", Color::Red.paint("Export failed:"), why);
+            process::exit(1);
+        }
+    }
+}
+
+fn run_prune(comic: &dyn Comic, matches: &ArgMatches, conf: ConfigFile, cache_dir: &String) {
+    let config = conf.read();
+    let mut limits = Limits::from_config(&config);
+    if let Some(max_bytes) = matches.get_one::<u64>("max_bytes") {
+        limits.max_bytes = Some(*max_bytes);
+    }
+    if let Some(keep_within) = matches.get_one::<usize>("keep_within") {
+        limits.keep_within = Some(*keep_within);
+    }
+    if limits.is_unlimited() {
+        eprintln!(
+            "There's nothing to prune to. Set `max_cache_bytes` or `keep_within_pages_of_bookmark` in the config file, or pass --max_bytes or --keep_within."
+        );
+        process::exit(1);
+    }
+
+    match prune(comic, &config, limits, cache_dir, &[]) {
+        Ok(report) => println!(
+            "{} {} images deleted, {} freed",
+            Color::Green.paint("Pruned:"),
+            report.removed,
+            human_bytes(report.freed_bytes)
+        ),
+        Err(why) => {
+            eprintln!("{} {}", Color::Red.paint("Couldn't prune the cache:"), why);
+            process::exit(1);
+        }
+    }
+}
+
+fn run_stats(cache_dir: &String) {
+    let stats = match stats(cache_dir) {
+        Ok(stats) => stats,
+        Err(why) => {
+            eprintln!("{} {}", Color::Red.paint("Couldn't read the cache:"), why);
+            process::exit(1);
+        }
+    };
+    let page = |id: &Option<String>| match id {
+        Some(id) => id_date(id).unwrap_or_else(|| format!("#{}", id)),
+        None => "-".to_string(),
+    };
+
+    println!("{:<10} {}", "Directory", cache_dir);
+    println!(
+        "{:<10} {} cached ({} in the index)",
+        "Pages", stats.pages, stats.indexed
+    );
+    println!("{:<10} {}", "Size", human_bytes(stats.bytes));
+    println!("{:<10} {}", "Oldest", page(&stats.oldest));
+    println!("{:<10} {}", "Newest", page(&stats.newest));
+}
+
+/// Something like `12.3 MiB`.
+fn human_bytes(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, units[0])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+async fn run_verify(comic: &dyn Comic, matches: &ArgMatches, cache_dir: &str) {
+    let jobs = *matches
+        .get_one::<usize>("jobs")
+        .expect("jobs has a default");
+
+    let verified = verify(comic, cache_dir.to_string(), jobs, |checked, total| {
+        print!("\r{} {}/{}", Color::Cyan.paint("Checking"), checked, total);
+        let _ = std::io::stdout().flush();
+    })
+    .await;
+    println!();
+
+    let report = match verified {
+        Ok(report) => report,
+        Err(why) => {
+            eprintln!("{} {}", Color::Red.paint("Couldn't verify the cache:"), why);
+            process::exit(1);
+        }
+    };
+
+    for (entry, why) in &report.corrupt {
+        println!(
+            "{} {} ({}): {}",
+            Color::Red.paint("Corrupt"),
+            entry.id,
+            entry.filename,
+            why
+        );
+    }
+    for entry in &report.missing {
+        println!(
+            "{} {} ({})",
+            Color::Red.paint("Missing"),
+            entry.id,
+            entry.filename
+        );
+    }
+    for entry in &report.unindexed {
+        println!("{} {}", Color::Yellow.paint("Unindexed"), entry.filename);
+    }
+    for orphan in &report.orphaned {
+        println!("{} {}", Color::Yellow.paint("Orphaned"), orphan);
+    }
+    for unknown in &report.unknown {
+        println!(
+            "{} {} (left alone)",
+            Color::Yellow.paint("Unknown"),
+            unknown
+        );
+    }
+    println!(
+        "{} images OK, {} corrupt, {} missing, {} unindexed, {} orphaned",
+        report.ok,
+        report.corrupt.len(),
+        report.missing.len(),
+        report.unindexed.len(),
+        report.orphaned.len()
+    );
+
+    if report.is_clean() {
+        return;
+    }
+    if !matches.get_flag("repair") {
+        eprintln!("Run `ggg cache verify --repair` to fix them.");
+        process::exit(1);
+    }
+
+    let repaired = repair(comic, cache_dir.to_string(), &report).await;
+    println!(
+        "{} {} images downloaded again, {} added to the index, {} orphaned files deleted",
+        Color::Green.paint("Repaired:"),
+        repaired.downloaded,
+        repaired.adopted,
+        repaired.removed
+    );
+    for (what, why) in &repaired.failed {
+        eprintln!("{} {}: {}", Color::Red.paint("Failed"), what, why);
+    }
+    if !repaired.failed.is_empty() {
+        process::exit(1);
+    }
+}
+
+async fn run_sync(comic: &dyn Comic, config: &Config, matches: &ArgMatches, cache_dir: &str) {
+    let jobs = *matches
+        .get_one::<usize>("jobs")
+        .expect("jobs has a default");
+
+    let synced = sync(comic, config, cache_dir.to_string(), jobs, |progress| {
+        print!(
+            "\r{} {} ({} already cached, {} downloading)",
+            Color::Cyan.paint("Syncing"),
+            progress.id,
+            progress.already_cached,
+            progress.queued
+        );
+        let _ = std::io::stdout().flush();
+    })
+    .await;
+    println!();
+
+    match synced {
+        Ok(report) => {
+            println!(
+                "{} {} pages downloaded, {} already cached",
+                Color::Green.paint("Done:"),
+                report.downloaded,
+                report.already_cached
+            );
+            for (id, why) in &report.failed {
+                eprintln!("{} {}: {}", Color::Red.paint("Failed"), id, why);
+            }
+            if !report.failed.is_empty() {
+                eprintln!("Run `ggg sync` again to retry the failed pages.");
+                process::exit(1);
+            }
+        }
+        Err(why) => {
+            eprintln!("{} {}", Color::Red.paint("Sync stopped:"), why);
+            eprintln!("Run `ggg sync` again to pick up where it left off.");
+            process::exit(1);
+        }
+    }
+}
+
 fn check_cache_dir(cache_dir: &String) {
     let dir = Path::new(cache_dir);
     if dir.exists() {
@@ -162,3 +729,47 @@ fn check_cache_dir(cache_dir: &String) {
     }
 }
 
+/// Makes (and returns) the cache directory for `comic` inside `cache_dir`.
+///
+/// Girl Genius used to be cached straight into `cache_dir`, so the first time its own directory
+/// is made, whatever it had cached there is moved in.
+fn comic_cache_dir(cache_dir: &str, comic: &dyn Comic) -> String {
+    let dir = Path::new(cache_dir).join(comic.name());
+    let is_new = !dir.exists();
+
+    if let Err(why) = fs::create_dir_all(&dir) {
+        eprintln!(
+            "Couldn't create the cache directory {}: {}",
+            dir.display(),
+            why
+        );
+        process::exit(1);
+    }
+
+    if is_new && comic.name() == GirlGenius.name() {
+        let old_files = fs::read_dir(cache_dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.is_file())
+            .filter(|p| {
+                p.file_name().is_some_and(|n| n == "pages.json")
+                    || p.extension().is_some_and(|e| e == "jpg")
+            });
+
+        for old in old_files {
+            let new = dir.join(old.file_name().unwrap());
+            if let Err(why) = fs::rename(&old, &new) {
+                eprintln!(
+                    "Couldn't move {} into {}: {}",
+                    old.display(),
+                    dir.display(),
+                    why
+                );
+            }
+        }
+    }
+
+    dir.to_string_lossy().to_string()
+}
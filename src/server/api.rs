@@ -0,0 +1,251 @@
+//! The JSON API. Moving around goes through the same functions as the app, so the reading
+//! position, bookmarks and cache all stay in step with it.
+
+use hyper::{body::HttpBody, header::CONTENT_TYPE, Body, Method, Request, Response, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    cache,
+    comic::PageMetadata,
+    goto, id_date, next, previous, resolve_page,
+    server::{json_response, Failure, Server},
+    structs::GggCacheData,
+    Page,
+};
+
+pub(crate) async fn handle(
+    server: &Server,
+    request: Request<Body>,
+    path: &[&str],
+) -> Result<Response<Body>, Failure> {
+    let comic = server.comic.as_ref();
+    let cache_dir = &server.cache_dir;
+    if request.method() != Method::GET {
+        require_json(&request)?;
+    }
+
+    match (request.method().clone(), path) {
+        (Method::GET, ["position"]) => Ok(position(server)),
+        (Method::POST, ["next"]) => {
+            let page = next(comic, server.conf.clone(), cache_dir, server.offline).await?;
+            moved(server, page, "This is the newest page")
+        }
+        (Method::POST, ["previous"]) => {
+            let page = previous(comic, server.conf.clone(), cache_dir, server.offline).await?;
+            moved(server, page, "This is the first page")
+        }
+        (Method::POST, ["goto"]) => {
+            let body = read_json::<GotoRequest>(request).await?;
+            let page = goto(
+                comic,
+                server.conf.clone(),
+                cache_dir,
+                body.page,
+                server.offline,
+            )
+            .await?;
+            moved(server, Some(page), "")
+        }
+        (Method::GET, ["pages", id]) => {
+            let id = comic.parse_id(id)?;
+            if GggCacheData::find(cache_dir, &id).is_none() {
+                return Err(Failure::not_found(format!(
+                    "There's no page {} in the cache",
+                    id
+                )));
+            }
+            Ok(json_response(&json!(PageInfo::new(server, &id))))
+        }
+
+        (Method::GET, ["bookmarks"]) => Ok(bookmarks(server)),
+        (Method::POST, ["bookmarks"]) => {
+            let body = read_json::<AddBookmark>(request).await?;
+            let page = match body.page {
+                Some(page) => resolve_page(comic, page, cache_dir, server.offline).await?,
+                None => server.conf.read().position(comic),
+            };
+            server.conf.add_bookmark(comic, &body.name, page)?;
+
+            let mut response = bookmarks(server);
+            *response.status_mut() = StatusCode::CREATED;
+            Ok(response)
+        }
+        (Method::POST, ["bookmarks", "activate"]) => {
+            let body = read_json::<ActivateBookmark>(request).await?;
+            server.conf.activate_bookmark(comic, body.name.as_deref())?;
+            Ok(position(server))
+        }
+        (Method::PATCH, ["bookmarks", name]) => {
+            let body = read_json::<RenameBookmark>(request).await?;
+            server.conf.rename_bookmark(comic, name, &body.name)?;
+            Ok(bookmarks(server))
+        }
+        (Method::DELETE, ["bookmarks", name]) => {
+            server.conf.remove_bookmark(comic, name)?;
+            Ok(bookmarks(server))
+        }
+
+        (Method::GET, ["cache"]) => {
+            let stats = cache::stats(cache_dir)?;
+            Ok(json_response(&json!({
+                "pages": stats.pages,
+                "indexed": stats.indexed,
+                "bytes": stats.bytes,
+                "oldest": stats.oldest,
+                "newest": stats.newest,
+                "offline": server.offline,
+            })))
+        }
+
+        _ => Err(Failure::not_found(format!(
+            "There's no {} /api/{}",
+            request.method(),
+            path.join("/")
+        ))),
+    }
+}
+
+/// A page, as far as the cache index knows it.
+#[derive(Debug, Serialize)]
+struct PageInfo {
+    id: String,
+    /// Volume, chapter, page in chapter, title and publish date, where they're known.
+    #[serde(flatten)]
+    metadata: PageMetadata,
+    /// `YYYY-MM-DD`: when it was published, or the id, for comics whose ids are dates.
+    date: Option<String>,
+    previous: Option<String>,
+    next: Option<String>,
+    /// Whether the image is on disk, so the `image` URL won't need to download it.
+    cached: bool,
+    image: String,
+    /// The page on the comic's site.
+    url: String,
+}
+
+impl PageInfo {
+    fn new(server: &Server, id: &str) -> PageInfo {
+        let data = GggCacheData::find(&server.cache_dir, id);
+        let cached = data
+            .as_ref()
+            .is_some_and(|d| d.is_cached(&server.cache_dir));
+        let (metadata, previous, next) = match data {
+            Some(data) => (data.metadata, data.previous, data.next),
+            None => (PageMetadata::default(), None, None),
+        };
+
+        PageInfo {
+            id: id.to_string(),
+            date: metadata.published.clone().or_else(|| id_date(id)),
+            metadata,
+            previous,
+            next,
+            cached,
+            image: format!("/images/{}", id),
+            url: server.comic.page_url(id),
+        }
+    }
+}
+
+/// Where the reader is, and which bookmark (if any) they're reading from.
+fn position(server: &Server) -> Response<Body> {
+    let config = server.conf.read();
+    let comic = server.comic.as_ref();
+
+    json_response(&json!({
+        "comic": comic.name(),
+        "title": comic.title(),
+        "page": PageInfo::new(server, &config.position(comic)),
+        "bookmark": config.active_bookmark(comic).map(|b| &b.name),
+    }))
+}
+
+/// Answers a move with the new position, and starts caching the pages after it.
+fn moved(server: &Server, page: Option<Page>, at_end: &str) -> Result<Response<Body>, Failure> {
+    let page = page.ok_or_else(|| Failure::not_found(at_end))?;
+    server.prefetch(page.data.id);
+
+    Ok(position(server))
+}
+
+fn bookmarks(server: &Server) -> Response<Body> {
+    let config = server.conf.read();
+    let comic = server.comic.as_ref();
+    let bookmarks = config
+        .bookmarks_for(comic)
+        .into_iter()
+        .map(|b| json!({ "name": b.name, "page": b.page }))
+        .collect::<Vec<serde_json::Value>>();
+
+    json_response(&json!({
+        "active": config.active_bookmark(comic).map(|b| &b.name),
+        "reading_position": config.reading_position(comic),
+        "bookmarks": bookmarks,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct GotoRequest {
+    page: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct AddBookmark {
+    name: String,
+    /// Defaults to the current position.
+    page: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RenameBookmark {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ActivateBookmark {
+    /// `null` goes back to the reading position.
+    name: Option<String>,
+}
+
+/// Far bigger than anything the API is sent.
+const MAX_BODY: usize = 64 * 1024;
+
+/// Anything that changes something has to be sent as JSON, even if it has no body. Browsers
+/// won't send that from another site without a CORS preflight (which is never answered), so a
+/// web page the reader happens to visit can't move their place or their bookmarks.
+fn require_json(request: &Request<Body>) -> Result<(), Failure> {
+    let is_json = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|c| c.to_str().ok())
+        .and_then(|c| c.split(';').next())
+        .is_some_and(|c| c.trim().eq_ignore_ascii_case("application/json"));
+
+    match is_json {
+        true => Ok(()),
+        false => Err(Failure::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Send `Content-Type: application/json`",
+        )),
+    }
+}
+
+async fn read_json<T: DeserializeOwned>(request: Request<Body>) -> Result<T, Failure> {
+    let mut body = request.into_body();
+    let mut bytes = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk
+            .map_err(|why| Failure::bad_request(format!("Couldn't read the request: {}", why)))?;
+        if bytes.len() + chunk.len() > MAX_BODY {
+            return Err(Failure::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Requests can't be bigger than {} bytes", MAX_BODY),
+            ));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    serde_json::from_slice(&bytes)
+        .map_err(|why| Failure::bad_request(format!("That isn't the JSON I expected: {}", why)))
+}
@@ -0,0 +1,189 @@
+//! `ggg serve`: a small web server for reading from another device, or driving ggg from a
+//! script. It serves a reader page, the cached images, a JSON API under `/api`, and an OPDS
+//! catalog under `/opds`.
+
+mod api;
+mod opds;
+
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use crate::{comic::Comic, page, pruned, structs::ConfigFile, Error, Prefetcher};
+use hyper::{
+    header::{CACHE_CONTROL, CONTENT_TYPE},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, StatusCode,
+};
+use percent_encoding::percent_decode_str;
+use serde_json::json;
+
+/// What the server reads from, and moves around in. It's the same config file and cache as
+/// everything else, so the app and the server can be used side by side.
+pub struct Server {
+    comic: Arc<dyn Comic>,
+    conf: ConfigFile,
+    cache_dir: String,
+    /// Only serve what's already cached.
+    offline: bool,
+    prefetcher: Prefetcher,
+}
+
+/// Serves until Ctrl+C is pressed.
+pub async fn serve(server: Server, address: SocketAddr) -> Result<(), Error> {
+    let server = Arc::new(server);
+    let make_service = make_service_fn(move |_| {
+        let server = server.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let server = server.clone();
+                async move { Ok::<_, Infallible>(server.handle(request).await) }
+            }))
+        }
+    });
+
+    let bound = hyper::Server::try_bind(&address).map_err(|why| Error::Serve(why.to_string()))?;
+    println!("Serving on http://{} (Ctrl+C to stop)", address);
+    println!("The OPDS catalog is at http://{}/opds", address);
+
+    bound
+        .serve(make_service)
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await
+        .map_err(|why| Error::Serve(why.to_string()))
+}
+
+impl Server {
+    pub fn new(
+        comic: Arc<dyn Comic>,
+        conf: ConfigFile,
+        cache_dir: String,
+        offline: bool,
+    ) -> Server {
+        Server {
+            comic,
+            conf,
+            cache_dir,
+            offline,
+            prefetcher: Prefetcher::default(),
+        }
+    }
+
+    /// Starts caching the pages after `from` in the background, like the app does.
+    pub(crate) fn prefetch(&self, from: String) {
+        self.prefetcher.restart(
+            self.comic.clone(),
+            self.conf.read(),
+            from,
+            self.cache_dir.clone(),
+            self.offline,
+        );
+    }
+
+    async fn handle(&self, request: Request<Body>) -> Response<Body> {
+        let path = request
+            .uri()
+            .path()
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(|s| percent_decode_str(s).decode_utf8_lossy().into_owned())
+            .collect::<Vec<String>>();
+        let path = path.iter().map(|s| &s[..]).collect::<Vec<&str>>();
+
+        let response = match (request.method(), &path[..]) {
+            (&Method::GET, []) => Ok(Response::builder()
+                .header(CONTENT_TYPE, "text/html; charset=utf-8")
+                .body(Body::from(READER))
+                .expect("the reader is a valid response")),
+            (&Method::GET, ["images", id]) => self.image(id).await,
+            (_, ["api", rest @ ..]) => api::handle(self, request, rest).await,
+            (&Method::GET, ["opds", rest @ ..]) => opds::handle(self, rest).await,
+            _ => Err(Failure::not_found("There's nothing here")),
+        };
+
+        response.unwrap_or_else(|failure| failure.response())
+    }
+
+    /// A page's image, downloading it first if it isn't cached.
+    async fn image(&self, id: &str) -> Result<Response<Body>, Failure> {
+        let id = self.comic.parse_id(id)?;
+        let page = page(self.comic.as_ref(), id, &self.cache_dir, self.offline).await?;
+        pruned(
+            self.comic.as_ref(),
+            &self.conf.read(),
+            &self.cache_dir,
+            &page,
+        );
+        let image = tokio::fs::read(&page.path)
+            .await
+            .map_err(|why| Error::io(page.path.clone(), why))?;
+
+        Ok(Response::builder()
+            .header(CONTENT_TYPE, page.format().media_type())
+            // a page's image never changes
+            .header(CACHE_CONTROL, "public, max-age=604800, immutable")
+            .body(Body::from(image))
+            .expect("an image is a valid response"))
+    }
+}
+
+/// Why a request couldn't be handled. It's sent back as `{"error": message}`.
+#[derive(Debug)]
+pub(crate) struct Failure {
+    status: StatusCode,
+    message: String,
+}
+
+impl Failure {
+    pub(crate) fn new(status: StatusCode, message: impl Into<String>) -> Failure {
+        Failure {
+            status,
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn not_found(message: impl Into<String>) -> Failure {
+        Failure::new(StatusCode::NOT_FOUND, message)
+    }
+
+    pub(crate) fn bad_request(message: impl Into<String>) -> Failure {
+        Failure::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    fn response(&self) -> Response<Body> {
+        let mut response = json_response(&json!({ "error": self.message }));
+        *response.status_mut() = self.status;
+        response
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(why: Error) -> Failure {
+        let status = match why {
+            Error::NotCached(_) => StatusCode::NOT_FOUND,
+            Error::Parse(_) | Error::Bookmark(_) => StatusCode::BAD_REQUEST,
+            // the comic's site is the one having problems
+            Error::Network(_)
+            | Error::Status { .. }
+            | Error::Scrape { .. }
+            | Error::NotAnImage { .. } => StatusCode::BAD_GATEWAY,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        Failure {
+            status,
+            message: why.to_string(),
+        }
+    }
+}
+
+pub(crate) fn json_response(value: &serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(value.to_string()))
+        .expect("JSON is a valid response")
+}
+
+/// A reader that works on phones: tap either side of the page (or use the arrow keys) to turn
+/// it. It's just a front end for the API.
+const READER: &str = include_str!("reader.html");
@@ -0,0 +1,322 @@
+//! An OPDS 1.2 catalog, so e-reader apps (KOReader, Panels, ...) can browse the comic by
+//! chapter or year and download each one as a CBZ, which is made from the cache on demand.
+
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use chrono::Utc;
+use hyper::{
+    body::Bytes,
+    header::{CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE},
+    Body, Response,
+};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use tokio::io::AsyncReadExt;
+
+use crate::{
+    compare_ids,
+    export::{cbz::export_cbz, escape, Range},
+    id_date, parse_date_id,
+    server::{Failure, Server},
+    structs::GggCacheData,
+    Error,
+};
+
+const NAVIGATION: &str = "application/atom+xml;profile=opds-catalog;kind=navigation";
+const ACQUISITION: &str = "application/atom+xml;profile=opds-catalog;kind=acquisition";
+const CBZ: &str = "application/vnd.comicbook+zip";
+
+pub(crate) async fn handle(server: &Server, path: &[&str]) -> Result<Response<Body>, Failure> {
+    match path {
+        [] => Ok(feed(NAVIGATION, root(server))),
+        ["chapters"] => Ok(feed(
+            ACQUISITION,
+            acquisition(server, "chapters", "By chapter", &chapters(server)),
+        )),
+        ["years"] => Ok(feed(
+            ACQUISITION,
+            acquisition(server, "years", "By year", &years(server)),
+        )),
+        ["chapters", file] => {
+            let name = cbz_name(file)?;
+            let group = chapters(server)
+                .into_iter()
+                .find(|g| g.name == name)
+                .ok_or_else(|| {
+                    Failure::not_found(format!("There's no chapter called `{}`", name))
+                })?;
+            let range = Range {
+                chapter: Some(group.name.clone()),
+                ..Range::default()
+            };
+            download(server, &group, &range).await
+        }
+        ["years", file] => {
+            let name = cbz_name(file)?;
+            let group = years(server)
+                .into_iter()
+                .find(|g| g.name == name)
+                .ok_or_else(|| Failure::not_found(format!("There are no pages from {}", name)))?;
+            let range = Range {
+                from: Some(group.first.clone()),
+                to: Some(group.last.clone()),
+                chapter: None,
+            };
+            download(server, &group, &range).await
+        }
+        _ => Err(Failure::not_found("There's nothing here")),
+    }
+}
+
+/// Pages that go in the same CBZ: a chapter, or a year.
+#[derive(Debug, Clone)]
+struct Group {
+    name: String,
+    first: String,
+    last: String,
+    pages: usize,
+}
+
+/// Every storyline in the cache index, in reading order.
+fn chapters(server: &Server) -> Vec<Group> {
+    let mut index = GggCacheData::read(server.cache_dir.clone());
+    index.sort_by(|a, b| compare_ids(&a.id, &b.id));
+
+    let mut groups: Vec<Group> = vec![];
+    for page in index {
+        let label = match page.metadata.chapter {
+            Some(chapter) => chapter,
+            None => continue,
+        };
+        // storylines can be interrupted (by filler, say), but they're still one chapter
+        match groups.iter_mut().find(|g| g.name == label) {
+            Some(group) => {
+                group.last = page.id;
+                group.pages += 1;
+            }
+            None => groups.push(Group {
+                name: label,
+                first: page.id.clone(),
+                last: page.id,
+                pages: 1,
+            }),
+        }
+    }
+    groups
+}
+
+/// Every year the cache index has pages from. Only comics whose ids are dates have any.
+fn years(server: &Server) -> Vec<Group> {
+    let mut years: BTreeMap<String, Group> = BTreeMap::new();
+    for page in GggCacheData::read(server.cache_dir.clone()) {
+        let year = match parse_date_id(&page.id) {
+            Ok(date) => date.format("%Y").to_string(),
+            Err(_) => continue,
+        };
+        let group = years.entry(year.clone()).or_insert_with(|| Group {
+            name: year,
+            first: page.id.clone(),
+            last: page.id.clone(),
+            pages: 0,
+        });
+        if compare_ids(&page.id, &group.first).is_lt() {
+            group.first = page.id.clone();
+        }
+        if compare_ids(&page.id, &group.last).is_gt() {
+            group.last = page.id.clone();
+        }
+        group.pages += 1;
+    }
+    years.into_values().collect()
+}
+
+/// Exports `group` to a temporary CBZ and streams it back, deleting it once it's been sent.
+async fn download(
+    server: &Server,
+    group: &Group,
+    range: &Range,
+) -> Result<Response<Body>, Failure> {
+    static DOWNLOADS: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "ggg-{}-{}.cbz",
+        std::process::id(),
+        DOWNLOADS.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    export_cbz(
+        server.comic.as_ref(),
+        &server.cache_dir,
+        range,
+        server.offline,
+        &path,
+        |_| {},
+    )
+    .await?;
+    let mut file = tokio::fs::File::open(&path)
+        .await
+        .map_err(|why| Error::io(path.display().to_string(), why))?;
+    let length = file
+        .metadata()
+        .await
+        .map_err(|why| Error::io(path.display().to_string(), why))?
+        .len();
+
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            match file.read(&mut buffer).await {
+                Ok(0) | Err(_) => break,
+                Ok(read) => {
+                    // the client hung up
+                    if sender
+                        .send_data(Bytes::copy_from_slice(&buffer[..read]))
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            }
+        }
+        remove(path).await;
+    });
+
+    let filename = format!("{} - {}.cbz", server.comic.title(), group.name).replace('"', "'");
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, CBZ)
+        .header(CONTENT_LENGTH, length)
+        .header(
+            CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+                filename.replace(|c: char| !c.is_ascii() || c.is_ascii_control(), "_"),
+                utf8_percent_encode(&filename, NON_ALPHANUMERIC)
+            ),
+        )
+        .body(body)
+        .expect("a CBZ is a valid response"))
+}
+
+async fn remove(path: PathBuf) {
+    if let Err(why) = tokio::fs::remove_file(&path).await {
+        eprintln!("Couldn't delete `{}`: {}", path.display(), why);
+    }
+}
+
+/// `Volume 1.cbz` -> `Volume 1`.
+fn cbz_name(file: &str) -> Result<&str, Failure> {
+    file.strip_suffix(".cbz")
+        .ok_or_else(|| Failure::not_found("Downloads end in `.cbz`"))
+}
+
+fn feed(kind: &str, xml: String) -> Response<Body> {
+    Response::builder()
+        .header(CONTENT_TYPE, kind)
+        .body(Body::from(xml))
+        .expect("a feed is a valid response")
+}
+
+/// The start of a feed, up to its entries.
+fn feed_head(server: &Server, id: &str, title: &str, path: &str, kind: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>
+<feed xmlns=\"http://www.w3.org/2005/Atom\" xmlns:opds=\"http://opds-spec.org/2010/catalog\">
+  <id>urn:ggg:{comic}:{id}</id>
+  <title>{title}</title>
+  <updated>{updated}</updated>
+  <author><name>ggg</name></author>
+  <link rel=\"self\" href=\"{path}\" type=\"{kind}\"/>
+  <link rel=\"start\" href=\"/opds\" type=\"{navigation}\"/>
+  <link rel=\"up\" href=\"/opds\" type=\"{navigation}\"/>
+",
+        comic = escape(server.comic.name()),
+        id = escape(id),
+        title = escape(title),
+        updated = now(),
+        path = escape(path),
+        kind = kind,
+        navigation = NAVIGATION,
+    )
+}
+
+fn root(server: &Server) -> String {
+    let mut xml = feed_head(server, "root", server.comic.title(), "/opds", NAVIGATION);
+    for (path, title, about) in [
+        ("chapters", "By chapter", "Each storyline, as one CBZ"),
+        ("years", "By year", "Each year's pages, as one CBZ"),
+    ] {
+        xml.push_str(&format!(
+            "  <entry>
+    <id>urn:ggg:{comic}:{path}</id>
+    <title>{title}</title>
+    <updated>{updated}</updated>
+    <content type=\"text\">{about}</content>
+    <link rel=\"subsection\" href=\"/opds/{path}\" type=\"{acquisition}\"/>
+  </entry>
+",
+            comic = escape(server.comic.name()),
+            path = path,
+            title = title,
+            updated = now(),
+            about = about,
+            acquisition = ACQUISITION,
+        ));
+    }
+    xml.push_str("</feed>\n");
+    xml
+}
+
+/// A feed of `groups`, each with a link to download it and its first page as the cover.
+fn acquisition(server: &Server, path: &str, title: &str, groups: &[Group]) -> String {
+    let mut xml = feed_head(
+        server,
+        path,
+        &format!("{}: {}", server.comic.title(), title),
+        &format!("/opds/{}", path),
+        ACQUISITION,
+    );
+    for group in groups {
+        let heading = |id: &str| id_date(id).unwrap_or_else(|| format!("#{}", id));
+        let updated = parse_date_id(&group.last)
+            .map(|d| d.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+            .unwrap_or_else(|_| now());
+        let cover = format!(
+            "/images/{}",
+            utf8_percent_encode(&group.first, NON_ALPHANUMERIC)
+        );
+
+        xml.push_str(&format!(
+            "  <entry>
+    <id>urn:ggg:{comic}:{path}:{id}</id>
+    <title>{name}</title>
+    <updated>{updated}</updated>
+    <content type=\"text\">{pages} pages, from {first} to {last}</content>
+    <link rel=\"http://opds-spec.org/image\" href=\"{cover}\"/>
+    <link rel=\"http://opds-spec.org/image/thumbnail\" href=\"{cover}\"/>
+    <link rel=\"http://opds-spec.org/acquisition\" href=\"/opds/{path}/{file}.cbz\" type=\"{cbz}\"/>
+  </entry>
+",
+            comic = escape(server.comic.name()),
+            path = path,
+            id = escape(&utf8_percent_encode(&group.name, NON_ALPHANUMERIC).to_string()),
+            name = escape(&group.name),
+            updated = updated,
+            pages = group.pages,
+            first = heading(&group.first),
+            last = heading(&group.last),
+            cover = cover,
+            file = utf8_percent_encode(&group.name, NON_ALPHANUMERIC),
+            cbz = CBZ,
+        ));
+    }
+    xml.push_str("</feed>\n");
+    xml
+}
+
+fn now() -> String {
+    Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
@@ -1,19 +1,168 @@
 use std::{
+    collections::HashMap,
     fs::{self, create_dir_all, read_to_string, write},
     path::Path,
     process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        LazyLock, Mutex,
+    },
+    time::SystemTime,
 };
 
-use chrono::{DateTime, Utc};
-use scraper::Selector;
 use serde::{Deserialize, Serialize};
 
-use crate::{date_to_gg_string, home_dir, parse_gg_string_for_date};
+use crate::{
+    comic::{Comic, PageMetadata},
+    girl_genius::GirlGenius,
+    home_dir,
+    image_format::ImageFormat,
+    Error,
+};
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Config {
     pub latest_page: String,
     pub cache_dir: String,
+    /// How many pages past the current one to download in the background.
+    #[serde(default = "default_prefetch_ahead")]
+    pub prefetch_ahead: usize,
+    #[serde(default)]
+    pub keys: Keybindings,
+    /// Where each comic other than Girl Genius is up to (Girl Genius uses `latest_page`).
+    #[serde(default)]
+    pub positions: HashMap<String, String>,
+    #[serde(default)]
+    pub bookmarks: Vec<Bookmark>,
+    /// The bookmark that navigation moves, for each comic. Comics without one move their
+    /// reading position instead.
+    #[serde(default)]
+    pub active_bookmarks: HashMap<String, String>,
+    /// Once the cache is bigger than this, images are deleted (see `eviction`) until it isn't.
+    #[serde(default)]
+    pub max_cache_bytes: Option<u64>,
+    /// Delete cached images more than this many pages from the reading position and every
+    /// bookmark.
+    #[serde(default)]
+    pub keep_within_pages_of_bookmark: Option<usize>,
+    /// Which images go first when the cache is over `max_cache_bytes`.
+    #[serde(default)]
+    pub eviction: Eviction,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Eviction {
+    /// The images that were looked at least recently.
+    #[default]
+    Lru,
+    /// The images furthest from the reading position and bookmarks.
+    Distance,
+}
+
+/// A named place in a comic, so an old arc can be reread without losing your place at the front.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Bookmark {
+    pub name: String,
+    /// The `Comic::name` of the comic it's in.
+    pub comic: String,
+    pub page: String,
+}
+
+impl Config {
+    /// The page the reader's up to in `comic`: the active bookmark's page if there is one,
+    /// otherwise the reading position (or the first page if they haven't started it).
+    pub fn position(&self, comic: &dyn Comic) -> String {
+        match self.active_bookmark(comic) {
+            Some(bookmark) => bookmark.page.clone(),
+            None => self.reading_position(comic),
+        }
+    }
+
+    /// Where the reader's up to in `comic`, ignoring bookmarks.
+    pub fn reading_position(&self, comic: &dyn Comic) -> String {
+        if comic.name() == GirlGenius.name() {
+            return self.latest_page.clone();
+        }
+
+        self.positions
+            .get(comic.name())
+            .cloned()
+            .unwrap_or_else(|| comic.first_page().to_string())
+    }
+
+    /// `comic`'s bookmarks, in the order they were made.
+    pub fn bookmarks_for(&self, comic: &dyn Comic) -> Vec<&Bookmark> {
+        self.bookmarks
+            .iter()
+            .filter(|b| b.comic == comic.name())
+            .collect()
+    }
+
+    pub fn bookmark(&self, comic: &dyn Comic, name: &str) -> Option<&Bookmark> {
+        self.bookmarks
+            .iter()
+            .find(|b| b.comic == comic.name() && b.name == name)
+    }
+
+    pub fn active_bookmark(&self, comic: &dyn Comic) -> Option<&Bookmark> {
+        self.active_bookmarks
+            .get(comic.name())
+            .and_then(|name| self.bookmark(comic, name))
+    }
+}
+
+fn default_prefetch_ahead() -> usize {
+    5
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            latest_page: GirlGenius.first_page().to_string(),
+            cache_dir: format!("{}/.cache/ggg", home_dir()),
+            prefetch_ahead: default_prefetch_ahead(),
+            keys: Keybindings::default(),
+            positions: HashMap::new(),
+            bookmarks: vec![],
+            active_bookmarks: HashMap::new(),
+            max_cache_bytes: None,
+            keep_within_pages_of_bookmark: None,
+            eviction: Eviction::default(),
+        }
+    }
+}
+
+/// Which keys do what in the GUI. Each key is written like `Left`, `h`, `PageDown` or
+/// `Alt+Left`, using the names of iced's `KeyCode`s (case doesn't matter).
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Keybindings {
+    pub prev: Vec<String>,
+    pub next: Vec<String>,
+    pub first: Vec<String>,
+    pub latest: Vec<String>,
+    pub goto: Vec<String>,
+    pub fullscreen: Vec<String>,
+    pub back: Vec<String>,
+    pub forward: Vec<String>,
+}
+
+impl Default for Keybindings {
+    fn default() -> Self {
+        let keys = |keys: &[&str]| keys.iter().map(|k| k.to_string()).collect();
+
+        Keybindings {
+            prev: keys(&["Left", "h", "PageUp"]),
+            next: keys(&["Right", "l", "PageDown", "Space"]),
+            first: keys(&["Home"]),
+            latest: keys(&["End"]),
+            goto: keys(&["g"]),
+            fullscreen: keys(&["f"]),
+            back: keys(&["Alt+Left"]),
+            forward: keys(&["Alt+Right"]),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -25,26 +174,14 @@ impl ConfigFile {
         // make sure path exists (if not, yell at user)
         if !Path::new(&self.path).exists() {
             // TODO: make it show an error in the GUI with this data, instead of crashing
-            if &self.path == &format!("{}/.config/ggg/config.json", home_dir()) {
+            if self.path == format!("{}/.config/ggg/config.json", home_dir()) {
                 let dir_created = create_dir_all(format!("{}/.config/ggg", home_dir()));
                 if let Err(why) = dir_created {
                     eprintln!("Couldn't create the config directory: {:?}", why);
                     process::exit(1);
                 };
 
-                let wrote = write(
-                    &self.path,
-                    serde_json::to_string(&Config {
-                        latest_page: "20021104".to_string(),
-                        cache_dir: format!("{}/.cache/ggg", home_dir()),
-                    })
-                    .expect("Couldn't serialize a new config file"),
-                );
-
-                if let Err(why) = wrote {
-                    eprintln!("Couldn't write to the empty config file: {}", why);
-                    process::exit(1);
-                }
+                self.write(&Config::default());
             } else {
                 eprintln!("There's no config file at that location.");
                 process::exit(1);
@@ -57,21 +194,11 @@ impl ConfigFile {
         }
 
         // make sure it's got valid config data in it (if not, check if it's empty and create it (if not empty, yell at user))
-        let file = read_to_string(self.clone().path).expect("Couldn't read config file");
+        let file = read_to_string(&self.path).expect("Couldn't read config file");
         if file.is_empty() {
-            let wrote = write(
-                &self.path,
-                serde_json::to_string(&Config {
-                    latest_page: "20021104".to_string(),
-                    cache_dir: format!("{}/.cache/ggg", home_dir()),
-                })
-                .expect("Couldn't serialize a new config file"),
-            );
-
-            if let Err(why) = wrote {
-                eprintln!("Couldn't write to the empty config file: {}", why);
-                process::exit(1);
-            }
+            let config = Config::default();
+            self.write(&config);
+            return config;
         };
 
         let deserialized: Result<Config, serde_json::Error> = serde_json::from_str(&file[..]);
@@ -85,181 +212,873 @@ impl ConfigFile {
         }
     }
 
-    pub fn update_latest_page(self, date: String) {
-        let mut read = self.read();
-        read.latest_page = date;
-        let string: String = serde_json::to_string(&read).expect("Couldn't serialize the config");
-        write(self.path, string).expect("Couldn't write to the config file");
+    /// Replaces the config file. It's written to a temporary file that's renamed into place, so
+    /// anything reading it at the same time (prefetching, the server, another ggg) gets the old
+    /// config or the new one, never half of one.
+    fn write(&self, config: &Config) {
+        static WRITES: AtomicUsize = AtomicUsize::new(0);
+        let string: String = serde_json::to_string(config).expect("Couldn't serialize the config");
+        let temp = format!(
+            "{}.{}-{}.tmp",
+            self.path,
+            process::id(),
+            WRITES.fetch_add(1, Ordering::Relaxed)
+        );
+
+        write(&temp, string).expect("Couldn't write to the config file");
+        fs::rename(&temp, &self.path).expect("Couldn't replace the config file");
+    }
+
+    /// Changes the config with `f`, writing it back if `f` succeeds. The config file's lock is
+    /// held the whole time, so two of these at once (the app and the server, say) can't undo
+    /// each other's changes.
+    fn edit<T>(&self, f: impl FnOnce(&mut Config) -> Result<T, Error>) -> Result<T, Error> {
+        let lock = fs::File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(format!("{}.lock", self.path))
+            .expect("Couldn't open the config file's lock");
+        // released when `lock` is closed, even if `f` panics
+        lock.lock().expect("Couldn't lock the config file");
+
+        let mut config = self.read();
+        let edited = f(&mut config)?;
+        self.write(&config);
+        Ok(edited)
+    }
+
+    /// Moves the active bookmark to `id`, or the reading position if there isn't one.
+    pub fn update_position(self, comic: &dyn Comic, id: String) {
+        self.edit(|read| {
+            let active = read.active_bookmark(comic).map(|b| b.name.clone());
+
+            match active {
+                Some(name) => {
+                    for bookmark in read.bookmarks.iter_mut() {
+                        if bookmark.comic == comic.name() && bookmark.name == name {
+                            bookmark.page = id.clone();
+                        }
+                    }
+                }
+                None if comic.name() == GirlGenius.name() => read.latest_page = id,
+                None => {
+                    read.positions.insert(comic.name().to_string(), id);
+                }
+            }
+            Ok(())
+        })
+        .expect("moving the position can't fail");
+    }
+
+    pub fn add_bookmark(&self, comic: &dyn Comic, name: &str, page: String) -> Result<(), Error> {
+        let name = name.trim();
+        self.edit(|read| {
+            if name.is_empty() {
+                return Err(Error::Bookmark("Bookmarks need a name".to_string()));
+            }
+            if read.bookmark(comic, name).is_some() {
+                return Err(Error::Bookmark(format!(
+                    "There's already a bookmark called `{}`",
+                    name
+                )));
+            }
+
+            read.bookmarks.push(Bookmark {
+                name: name.to_string(),
+                comic: comic.name().to_string(),
+                page,
+            });
+            Ok(())
+        })
+    }
+
+    pub fn rename_bookmark(&self, comic: &dyn Comic, from: &str, to: &str) -> Result<(), Error> {
+        let to = to.trim();
+        self.edit(|read| {
+            if to.is_empty() {
+                return Err(Error::Bookmark("Bookmarks need a name".to_string()));
+            }
+            if read.bookmark(comic, from).is_none() {
+                return Err(Error::Bookmark(format!(
+                    "There's no bookmark called `{}`",
+                    from
+                )));
+            }
+            if from != to && read.bookmark(comic, to).is_some() {
+                return Err(Error::Bookmark(format!(
+                    "There's already a bookmark called `{}`",
+                    to
+                )));
+            }
+
+            for bookmark in read.bookmarks.iter_mut() {
+                if bookmark.comic == comic.name() && bookmark.name == from {
+                    bookmark.name = to.to_string();
+                }
+            }
+            if read.active_bookmarks.get(comic.name()).map(|a| &a[..]) == Some(from) {
+                read.active_bookmarks
+                    .insert(comic.name().to_string(), to.to_string());
+            }
+            Ok(())
+        })
+    }
+
+    /// Deletes a bookmark. If it was the active one, navigation goes back to moving the reading
+    /// position.
+    pub fn remove_bookmark(&self, comic: &dyn Comic, name: &str) -> Result<(), Error> {
+        self.edit(|read| {
+            if read.bookmark(comic, name).is_none() {
+                return Err(Error::Bookmark(format!(
+                    "There's no bookmark called `{}`",
+                    name
+                )));
+            }
+
+            read.bookmarks
+                .retain(|b| !(b.comic == comic.name() && b.name == name));
+            if read.active_bookmarks.get(comic.name()).map(|a| &a[..]) == Some(name) {
+                read.active_bookmarks.remove(comic.name());
+            }
+            Ok(())
+        })
+    }
+
+    /// Makes `name` the bookmark that navigation moves (or the reading position, for `None`),
+    /// and returns the page it's at.
+    pub fn activate_bookmark(
+        &self,
+        comic: &dyn Comic,
+        name: Option<&str>,
+    ) -> Result<String, Error> {
+        self.edit(|read| {
+            match name {
+                Some(name) => {
+                    if read.bookmark(comic, name).is_none() {
+                        return Err(Error::Bookmark(format!(
+                            "There's no bookmark called `{}`",
+                            name
+                        )));
+                    }
+                    read.active_bookmarks
+                        .insert(comic.name().to_string(), name.to_string());
+                }
+                None => {
+                    read.active_bookmarks.remove(comic.name());
+                }
+            }
+            Ok(read.position(comic))
+        })
     }
 }
 
+/// One page of a comic, fetched from its site.
 #[derive(Debug)]
-pub struct GirlGeniusPage {
+pub struct ComicPage {
+    /// `None` for pages (like the front page) whose URL doesn't say which page they are.
+    pub id: Option<String>,
     pub current_url: String,
     pub current_image: String,
-    pub previous_url: Option<String>,
-    pub next_url: Option<String>,
+    /// The previous page's id.
+    pub previous: Option<String>,
+    /// The next page's id.
+    pub next: Option<String>,
+    /// The newest page's id, if the page links to it.
+    pub last: Option<String>,
+    pub metadata: PageMetadata,
 }
-impl GirlGeniusPage {
-    pub async fn new(date: DateTime<Utc>) -> GirlGeniusPage {
-        let url = date_to_gg_string(date);
-        let result_option = reqwest::get(url).await;
-
-        let result = match result_option {
-            Ok(r) => r,
-            Err(why) => {
-                eprintln!("Couldn't fetch a GG page's HTML: {:?}", why);
-                process::exit(1);
-            }
-        };
+impl ComicPage {
+    pub async fn new(comic: &dyn Comic, id: &str) -> Result<ComicPage, Error> {
+        ComicPage::from_url(comic, comic.page_url(id)).await
+    }
 
-        let text = match result.text().await {
-            Ok(t) => t,
-            Err(why) => {
-                eprintln!("Couldn't get the text of a GG page: {:?}", why);
-                process::exit(1);
-            }
-        };
+    /// The comic's newest page.
+    pub async fn latest(comic: &dyn Comic) -> Result<ComicPage, Error> {
+        ComicPage::from_url(comic, comic.latest_url()).await
+    }
+
+    async fn from_url(comic: &dyn Comic, url: String) -> Result<ComicPage, Error> {
+        let result = reqwest::get(url.clone()).await?;
+
+        if !result.status().is_success() {
+            return Err(Error::Status {
+                url,
+                status: result.status(),
+            });
+        }
+
+        // if the site redirected us to a different page, that's the page we're actually on
+        let final_url = result.url().clone();
+        let id = comic
+            .page_id(final_url.as_str())
+            .or_else(|| comic.page_id(&url));
+
+        let text = result.text().await?;
 
         // println!("{}", text);
 
         let html = scraper::Html::parse_document(&text[..]);
-        let selector = scraper::Selector::parse("div[id=\"topnav\"] > a").unwrap();
-
-        let topnav_data = html
-            .select(&selector)
-            .map(|c| GirlGeniusTopnavData {
-                id: c.value().attr("id").unwrap().to_string(),
-                href: c.value().attr("href").unwrap().to_string(),
-            })
-            .collect::<Vec<GirlGeniusTopnavData>>();
-
-        let image_selector = Selector::parse("img[alt=\"Comic\"]").unwrap();
-        let image_url_vec = html
-            .select(&image_selector)
-            .map(|c| c.value().attr("src").unwrap().to_string())
-            .collect::<Vec<String>>();
-
-        let image_url = &image_url_vec[0];
-
-        // println!("{}", image_url);
-        let next_url = if topnav_data.iter().find(|d| d.id == "topnext").is_some() {
-            Some(
-                topnav_data
-                    .iter()
-                    .find(|d| d.id == "topnext")
-                    .expect("literally how")
-                    .href
-                    .clone(),
-            )
-        } else {
-            None
-        };
+        let scraped = comic.scrape(final_url.as_str(), &html)?;
 
-        let previous_url = if topnav_data.iter().find(|d| d.id == "topprev").is_some() {
-            Some(
-                topnav_data
-                    .iter()
-                    .find(|d| d.id == "topprev")
-                    .expect("literally how")
-                    .href
-                    .clone(),
-            )
-        } else {
-            None
+        // image links might be relative to the page
+        let image = &scraped.images[0];
+        let current_image = match final_url.join(image) {
+            Ok(absolute) => absolute.to_string(),
+            Err(_) => image.clone(),
         };
+        let link = |url: &Option<String>| url.as_ref().and_then(|u| comic.page_id(u));
 
-        GirlGeniusPage {
-            current_url: date_to_gg_string(date),
-            current_image: image_url.clone().to_string(),
-            next_url,
-            previous_url,
-        }
+        Ok(ComicPage {
+            current_url: final_url.to_string(),
+            current_image,
+            previous: link(&scraped.previous_url),
+            next: link(&scraped.next_url),
+            last: link(&scraped.last_url),
+            metadata: scraped.metadata,
+            id,
+        })
     }
 
-    pub async fn save(&self, cache_dir: &String) -> String {
-        let filename = format!(
-            "{}.jpg",
-            parse_gg_string_for_date(self.current_url.clone())
-                .format("%Y%m%d")
-                .to_string()
-        );
+    pub fn id(&self) -> Result<&String, Error> {
+        self.id.as_ref().ok_or_else(|| {
+            Error::Parse(format!(
+                "Couldn't tell which page `{}` is",
+                self.current_url
+            ))
+        })
+    }
+
+    /// Downloads the image (unless it's already cached) and records the page in the cache
+    /// index. The file is named after the image's real format, and anything that isn't an
+    /// image is refused.
+    pub async fn save(&self, cache_dir: &String) -> Result<GggCacheData, Error> {
+        let (data, _) = self.download(cache_dir).await?;
+        data.record(cache_dir)?;
+
+        Ok(data)
+    }
+
+    /// Like `save`, but leaves recording the page to the caller, so lots of pages can be
+    /// recorded with one write of the index (see `GggCacheData::record_all`). Also says whether
+    /// the image actually had to be fetched.
+    pub async fn download(&self, cache_dir: &String) -> Result<(GggCacheData, bool), Error> {
+        let id = self.id()?;
+        let cached = GggCacheData::find(cache_dir, id)
+            .filter(|d| d.is_cached(cache_dir))
+            .map(|d| d.filename)
+            .or_else(|| unrecorded_image(cache_dir, id));
+        if let Some(filename) = cached {
+            return Ok((self.cache_data(filename)?, false));
+        }
+
+        let response = reqwest::get(self.current_image.clone()).await?;
+        if !response.status().is_success() {
+            return Err(Error::Status {
+                url: self.current_image.clone(),
+                status: response.status(),
+            });
+        }
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|c| c.to_str().ok())
+            .map(|c| c.to_string());
+        let image = response.bytes().await?;
+        let format = ImageFormat::detect(&self.current_image, content_type.as_deref(), &image)?;
+
+        let filename = format!("{}.{}", id, format.extension());
         let filepath = format!("{}/{}", cache_dir, filename);
-        if !self.check(cache_dir) {
-            println!("check is false, downloading image");
-            let image = match reqwest::get(self.current_image.clone()).await {
-                Ok(image) => match image.bytes().await {
-                    Ok(bytes) => bytes,
-                    Err(why) => {
-                        eprintln!(
-                            "Couldn't get the bytes of the current page's image: {:?}",
-                            why
-                        );
-                        process::exit(1);
-                    }
-                },
-                Err(why) => {
-                    eprintln!("Couldn't get the current image: {:?}", why);
-                    process::exit(1);
-                }
-            };
+        // an interrupted write leaves a stray `.tmp` file, never a truncated image
+        let temp = format!("{}/.{}.tmp", cache_dir, filename);
+        fs::write(&temp, image).map_err(|why| Error::io(temp.clone(), why))?;
+        fs::rename(&temp, &filepath).map_err(|why| Error::io(filepath.clone(), why))?;
 
-            // println!("{}", yansi::Color::Magenta.paint(filename.clone()));
+        Ok((self.cache_data(filename)?, true))
+    }
 
-            // println!("{}", yansi::Color::Magenta.paint(filepath.clone()));
-            let wrote = fs::write(filepath.clone(), image);
-            if let Err(why) = wrote {
-                eprintln!(
-                    "Couldn't save the image to `{}/{}`: {:?}",
-                    cache_dir, filename, why
-                );
-                process::exit(1);
-            }
-        };
+    /// This page's entry for the cache index, for an image saved as `filename`.
+    fn cache_data(&self, filename: String) -> Result<GggCacheData, Error> {
+        Ok(GggCacheData {
+            id: self.id()?.clone(),
+            filename,
+            previous: self.previous.clone(),
+            next: self.next.clone(),
+            metadata: self.metadata.clone(),
+        })
+    }
 
-        filepath.clone()
+    /// Whether this page's image is already cached.
+    pub fn check(&self, cache_dir: &String) -> Result<bool, Error> {
+        Ok(GggCacheData::find(cache_dir, self.id()?).is_some_and(|d| d.is_cached(cache_dir)))
     }
-    pub fn check(&self, cache_dir: &String) -> bool {
-        let filename = format!(
-            "{}.jpg",
-            parse_gg_string_for_date(self.current_url.clone())
-                .format("%Y%m%d")
-                .to_string()
-        );
+}
 
-        let contents = fs::read_dir(cache_dir)
-            .expect("ok please just don't mess with the cache folder when the program is running");
-        let collected_contents: Vec<String> = contents
-            .into_iter()
-            .map(|f| f.unwrap().file_name().to_str().unwrap().to_string())
-            .collect::<Vec<String>>();
+/// An image for page `id` that's on disk but not in the index, like one a sync downloaded just
+/// before it was stopped. It only counts if it decodes; a broken one is downloaded again.
+fn unrecorded_image(cache_dir: &str, id: &str) -> Option<String> {
+    ImageFormat::ALL
+        .iter()
+        .map(|format| format!("{}.{}", id, format.extension()))
+        .find(|filename| {
+            crate::verify::decode(Path::new(&format!("{}/{}", cache_dir, filename))).is_ok()
+        })
+}
+
+/// The cache indexes we've loaded, by cache directory, so looking up a page doesn't mean
+/// reading and parsing `pages.json` all over again.
+static INDEXES: LazyLock<Mutex<HashMap<String, LoadedIndex>>> = LazyLock::new(Default::default);
+
+/// One cache directory's `pages.json`, in memory.
+#[derive(Debug)]
+struct LoadedIndex {
+    pages: Vec<GggCacheData>,
+    /// Where each page is in `pages`.
+    by_id: HashMap<String, usize>,
+    /// What `pages.json` looked like when it was loaded (or last written by us).
+    stamp: Option<FileStamp>,
+}
+
+impl LoadedIndex {
+    fn new(pages: Vec<GggCacheData>, stamp: Option<FileStamp>) -> LoadedIndex {
+        let by_id = pages
+            .iter()
+            .enumerate()
+            .map(|(n, p)| (p.id.clone(), n))
+            .collect();
+
+        LoadedIndex {
+            pages,
+            by_id,
+            stamp,
+        }
+    }
+
+    fn get(&self, id: &str) -> Option<&GggCacheData> {
+        self.by_id.get(id).map(|&n| &self.pages[n])
+    }
+
+    /// Writes `pages` out as the whole index, and keeps them as the in-memory copy.
+    fn save(&mut self, cache_dir: &str, pages: Vec<GggCacheData>) -> Result<(), Error> {
+        let path = index_path(cache_dir);
+        let temp = format!("{}.tmp", path);
+        let json = serde_json::to_string(&pages).expect("Couldn't serialize the cache index");
 
-        // false
+        // writing to a temporary file first means a crash halfway through can't leave a
+        // truncated index behind. Renaming keeps the file's stamp, so it's taken beforehand,
+        // when it can't have been touched by anyone else yet.
+        fs::write(&temp, json).map_err(|why| Error::io(temp.clone(), why))?;
+        let stamp = FileStamp::of(&temp);
+        fs::rename(&temp, &path).map_err(|why| Error::io(path, why))?;
 
-        collected_contents.contains(&filename)
+        *self = LoadedIndex::new(pages, stamp);
+        Ok(())
     }
 }
 
-#[derive(Debug)]
-struct GirlGeniusTopnavData {
-    id: String,
-    href: String,
+/// Enough about a file to tell whether it's been rewritten since we last looked. Every write
+/// is a rename, so the inode changes even when the size and modification time happen not to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FileStamp {
+    modified: Option<SystemTime>,
+    len: u64,
+    #[cfg(unix)]
+    inode: u64,
+}
+
+impl FileStamp {
+    fn of(path: &str) -> Option<FileStamp> {
+        let metadata = fs::metadata(path).ok()?;
+
+        Some(FileStamp {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+            #[cfg(unix)]
+            inode: std::os::unix::fs::MetadataExt::ino(&metadata),
+        })
+    }
+}
+
+fn index_path(cache_dir: &str) -> String {
+    format!("{}/pages.json", cache_dir)
+}
+
+/// Runs `f` on the in-memory index for `cache_dir`, (re)loading it first if `pages.json` has
+/// changed since we last saw it, which is how writes from other ggg processes are picked up.
+/// Holds the lock the whole time, so prefetching and the reader can't clobber each other.
+fn with_index<T>(cache_dir: &str, f: impl FnOnce(&mut LoadedIndex) -> T) -> T {
+    let mut indexes = INDEXES.lock().unwrap_or_else(|e| e.into_inner());
+    let path = index_path(cache_dir);
+    let stamp = FileStamp::of(&path);
+
+    let index = indexes
+        .entry(cache_dir.to_string())
+        .and_modify(|index| {
+            if index.stamp != stamp {
+                *index = LoadedIndex::new(read_index_file(&path), stamp.clone());
+            }
+        })
+        .or_insert_with(|| LoadedIndex::new(read_index_file(&path), stamp.clone()));
+
+    f(index)
 }
 
+/// Like `with_index`, for changing the index. `pages.json.lock` is held from the reload to the
+/// write, so another ggg process (a `ggg sync` next to the app, say) can't write in between
+/// and have its pages lost.
+fn edit_index<T>(
+    cache_dir: &str,
+    f: impl FnOnce(&mut LoadedIndex) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let path = format!("{}/pages.json.lock", cache_dir);
+    let lock = fs::File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .map_err(|why| Error::io(path.clone(), why))?;
+    // released when `lock` is closed, even if `f` panics
+    lock.lock().map_err(|why| Error::io(path, why))?;
+
+    with_index(cache_dir, f)
+}
+
+/// Numbers the page at `at` within its chapter, if it isn't already: it's page 1 if the page
+/// before it is in another chapter (or there isn't one), and one more than the page before it
+/// otherwise. Then carries on through the pages after it that the new number lets us count.
+/// Pages after a gap in the index stay unnumbered until the gap is filled.
+fn number_pages(pages: &mut [GggCacheData], find: impl Fn(&str) -> Option<usize>, at: usize) {
+    let mut current = at;
+
+    // links can't go round in circles, but a broken index shouldn't hang us if they do
+    for _ in 0..pages.len() {
+        let page = &pages[current];
+        if page.metadata.page_in_chapter.is_none() && page.chapter().is_some() {
+            let number = match page.previous.as_deref() {
+                None => Some(1),
+                Some(previous) => {
+                    find(previous).and_then(|p| match pages[p].chapter() == page.chapter() {
+                        true => pages[p].metadata.page_in_chapter.map(|n| n + 1),
+                        false => Some(1),
+                    })
+                }
+            };
+            pages[current].metadata.page_in_chapter = number;
+        }
+
+        let next = match pages[current].next.as_deref().and_then(&find) {
+            Some(next) => next,
+            None => break,
+        };
+        let carries_on = pages[current].metadata.page_in_chapter.is_some()
+            || pages[next].chapter() != pages[current].chapter();
+        if pages[next].metadata.page_in_chapter.is_some() || !carries_on {
+            break;
+        }
+        current = next;
+    }
+}
+
+/// Reads `pages.json` from disk. A missing index is just an empty one, and a corrupt one gets
+/// moved out of the way (to `pages.json.corrupt`) so it can be rebuilt from scratch.
+fn read_index_file(path: &str) -> Vec<GggCacheData> {
+    let json = match fs::read_to_string(path) {
+        Ok(json) => json,
+        Err(_) => return vec![],
+    };
+
+    match serde_json::from_str(&json[..]) {
+        Ok(vec) => vec,
+        Err(why) => {
+            eprintln!(
+                "The cache index at `{}` is corrupt, starting a new one: {}",
+                path, why
+            );
+            let _ = fs::rename(path, format!("{}.corrupt", path));
+            vec![]
+        }
+    }
+}
+
+/// One entry in the cache's `pages.json` index: a page that's been downloaded, and the pages
+/// on either side of it (if we know them yet).
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "StoredCacheData")]
 pub struct GggCacheData {
     pub id: String,
+    /// The image's file in the cache directory. Its extension is the image's real format.
     pub filename: String,
-    pub previous: String,
-    pub next: String,
+    pub previous: Option<String>,
+    pub next: Option<String>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub metadata: PageMetadata,
+}
+
+fn is_default(metadata: &PageMetadata) -> bool {
+    *metadata == PageMetadata::default()
+}
+
+/// An index entry as it's stored, which might be from before `PageMetadata`, when the chapter
+/// was all there was and it was called `label`.
+#[derive(Deserialize)]
+struct StoredCacheData {
+    id: String,
+    filename: String,
+    #[serde(default)]
+    previous: Option<String>,
+    #[serde(default)]
+    next: Option<String>,
+    #[serde(default)]
+    label: Option<String>,
+    #[serde(default)]
+    metadata: PageMetadata,
+}
+
+impl From<StoredCacheData> for GggCacheData {
+    fn from(stored: StoredCacheData) -> GggCacheData {
+        let mut metadata = stored.metadata;
+        if metadata.chapter.is_none() {
+            metadata.chapter = stored.label;
+        }
+
+        GggCacheData {
+            id: stored.id,
+            filename: stored.filename,
+            previous: stored.previous,
+            next: stored.next,
+            metadata,
+        }
+    }
 }
 
 impl GggCacheData {
+    /// An entry for a file named the way we name cached images (`<id>.<extension>`), whether
+    /// or not the index knows about it. Its links and metadata are filled in the next time the
+    /// page is fetched.
+    pub fn from_filename(comic: &dyn Comic, filename: &str) -> Option<GggCacheData> {
+        let (id, extension) = filename.rsplit_once('.')?;
+        ImageFormat::from_extension(extension)?;
+        if comic.parse_id(id).ok()? != id {
+            return None;
+        }
+
+        Some(GggCacheData {
+            id: id.to_string(),
+            filename: filename.to_string(),
+            previous: None,
+            next: None,
+            metadata: PageMetadata::default(),
+        })
+    }
+
+    /// The storyline/chapter the page belongs to, if the site says.
+    pub fn chapter(&self) -> Option<&str> {
+        self.metadata.chapter.as_deref()
+    }
+
+    /// The whole index.
     pub fn read(cache_dir: String) -> Vec<GggCacheData> {
-        let json = fs::read_to_string(format!("{}/pages.json", cache_dir)).expect("fix this later, nerd");
+        with_index(&cache_dir, |index| index.pages.clone())
+    }
+
+    /// Replaces the whole index.
+    pub fn write(cache_dir: &str, pages: &[GggCacheData]) -> Result<(), Error> {
+        edit_index(cache_dir, |index| index.save(cache_dir, pages.to_vec()))
+    }
+
+    pub fn find(cache_dir: &str, id: &str) -> Option<GggCacheData> {
+        with_index(cache_dir, |index| index.get(id).cloned())
+    }
+
+    /// Adds (or updates) this page's entry, and fills in the matching link on its neighbours.
+    /// Links and metadata we already know are never replaced with `None`. Pages the site doesn't
+    /// number are numbered within their chapter as the pages before them turn up.
+    pub fn record(&self, cache_dir: &str) -> Result<(), Error> {
+        GggCacheData::record_all(cache_dir, std::slice::from_ref(self))
+    }
+
+    /// Records every one of `entries`, like `record`, but writes the index once for all of them.
+    pub fn record_all(cache_dir: &str, entries: &[GggCacheData]) -> Result<(), Error> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        edit_index(cache_dir, |index| {
+            let mut pages = index.pages.clone();
+            let mut by_id = index.by_id.clone();
+            for entry in entries {
+                entry.merge_into(&mut pages, &mut by_id);
+            }
+
+            index.save(cache_dir, pages)
+        })
+    }
+
+    /// `record`, on an index that's being edited. `by_id` is kept up to date with `pages`.
+    fn merge_into(&self, pages: &mut Vec<GggCacheData>, by_id: &mut HashMap<String, usize>) {
+        let at = match by_id.get(&self.id) {
+            Some(&n) => {
+                let existing = &mut pages[n];
+                existing.filename = self.filename.clone();
+                if self.previous.is_some() {
+                    existing.previous = self.previous.clone();
+                }
+                if self.next.is_some() {
+                    existing.next = self.next.clone();
+                }
+                existing.metadata.merge(&self.metadata);
+                n
+            }
+            None => {
+                pages.push(self.clone());
+                by_id.insert(self.id.clone(), pages.len() - 1);
+                pages.len() - 1
+            }
+        };
+
+        if let Some(&n) = self.previous.as_ref().and_then(|p| by_id.get(p)) {
+            pages[n].next.get_or_insert_with(|| self.id.clone());
+        }
+        if let Some(&n) = self.next.as_ref().and_then(|p| by_id.get(p)) {
+            pages[n].previous.get_or_insert_with(|| self.id.clone());
+        }
+
+        number_pages(pages, |id| by_id.get(id).copied(), at);
+    }
+
+    /// Fixes the images cached before formats were checked, when everything was saved as
+    /// `<id>.jpg` and nothing was indexed: each one is renamed after its real format and recorded
+    /// in the index, and ones that aren't images at all are deleted, so they're downloaded
+    /// again. Only runs once per cache directory. Returns how many files were renamed or deleted.
+    pub fn migrate_formats(comic: &dyn Comic, cache_dir: &String) -> Result<usize, Error> {
+        let marker = format!("{}/.formats-checked", cache_dir);
+        if Path::new(&marker).exists() {
+            return Ok(0);
+        }
+
+        let files = fs::read_dir(cache_dir).map_err(|why| Error::io(cache_dir.clone(), why))?;
+        let old = files
+            .filter_map(|f| f.ok())
+            .filter_map(|f| f.file_name().to_str().map(|s| s.to_string()))
+            .filter(|f| f.ends_with(".jpg"))
+            .filter_map(|f| GggCacheData::from_filename(comic, &f));
+
+        let mut found = vec![];
+        let mut fixed = 0;
+        for mut entry in old {
+            match entry.match_extension(cache_dir)? {
+                Some(renamed) => {
+                    if renamed {
+                        fixed += 1;
+                    }
+                    found.push(entry);
+                }
+                None => {
+                    let path = entry.path(cache_dir);
+                    fs::remove_file(&path).map_err(|why| Error::io(path, why))?;
+                    fixed += 1;
+                }
+            }
+        }
+
+        GggCacheData::record_all(cache_dir, &found)?;
+        fs::write(&marker, "").map_err(|why| Error::io(marker, why))?;
+
+        Ok(fixed)
+    }
+
+    /// Renames the image if its extension isn't its real format. Returns whether it was
+    /// renamed, or `None` if it isn't an image at all.
+    pub fn match_extension(&mut self, cache_dir: &String) -> Result<Option<bool>, Error> {
+        let path = self.path(cache_dir);
+        let format = match ImageFormat::of_file(&path)? {
+            Some(format) => format,
+            None => return Ok(None),
+        };
+
+        let filename = format!("{}.{}", self.id, format.extension());
+        if filename == self.filename {
+            return Ok(Some(false));
+        }
+        let new_path = format!("{}/{}", cache_dir, filename);
+        fs::rename(&path, &new_path).map_err(|why| Error::io(path, why))?;
+        self.filename = filename;
+
+        Ok(Some(true))
+    }
+
+    pub fn path(&self, cache_dir: &String) -> String {
+        format!("{}/{}", cache_dir, self.filename)
+    }
+
+    /// Marks the image as just used, for `Eviction::Lru`. It's only a hint, so failing is fine.
+    pub fn touch(&self, cache_dir: &String) {
+        let _ = fs::File::options()
+            .write(true)
+            .open(self.path(cache_dir))
+            .and_then(|file| file.set_modified(std::time::SystemTime::now()));
+    }
+
+    /// Whether this entry's image is actually on disk.
+    pub fn is_cached(&self, cache_dir: &String) -> bool {
+        Path::new(&self.path(cache_dir)).is_file()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: &str, previous: Option<&str>, next: Option<&str>, chapter: &str) -> GggCacheData {
+        GggCacheData {
+            id: id.to_string(),
+            filename: format!("{}.png", id),
+            previous: previous.map(|p| p.to_string()),
+            next: next.map(|n| n.to_string()),
+            metadata: PageMetadata {
+                chapter: (!chapter.is_empty()).then(|| chapter.to_string()),
+                ..PageMetadata::default()
+            },
+        }
+    }
 
-        let vec: Vec<GggCacheData> = serde_json::from_str(&json[..]).expect("nightmare");
-        
-        vec
+    fn numbers(pages: &[GggCacheData]) -> Vec<Option<u32>> {
+        pages.iter().map(|p| p.metadata.page_in_chapter).collect()
     }
-}
\ No newline at end of file
+
+    fn number_from(pages: &mut [GggCacheData], at: usize) {
+        let ids = pages.iter().map(|p| p.id.clone()).collect::<Vec<String>>();
+        number_pages(pages, |id| ids.iter().position(|i| i == id), at);
+    }
+
+    #[test]
+    fn numbers_restart_with_each_chapter() {
+        let mut pages = vec![
+            entry("1", None, Some("2"), "One"),
+            entry("2", Some("1"), Some("3"), "One"),
+            entry("3", Some("2"), Some("4"), "Two"),
+            entry("4", Some("3"), None, "Two"),
+        ];
+        number_from(&mut pages, 0);
+
+        assert_eq!(numbers(&pages), [Some(1), Some(2), Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn pages_after_a_gap_wait_for_it_to_be_filled() {
+        // 2 isn't in the index yet
+        let mut pages = vec![
+            entry("3", Some("2"), Some("4"), "One"),
+            entry("4", Some("3"), None, "One"),
+        ];
+        number_from(&mut pages, 0);
+        assert_eq!(numbers(&pages), [None, None]);
+
+        pages.push(entry("2", None, Some("3"), "One"));
+        number_from(&mut pages, 2);
+        assert_eq!(numbers(&pages), [Some(2), Some(3), Some(1)]);
+    }
+
+    #[test]
+    fn a_new_chapter_after_a_gap_is_numbered_anyway() {
+        let mut pages = vec![
+            entry("3", Some("2"), Some("4"), "One"),
+            entry("4", Some("3"), None, "Two"),
+        ];
+        number_from(&mut pages, 0);
+
+        assert_eq!(numbers(&pages), [None, Some(1)]);
+    }
+
+    #[test]
+    fn numbering_stops_at_pages_that_already_have_a_number() {
+        let mut pages = vec![
+            entry("1", None, Some("2"), "One"),
+            entry("2", Some("1"), None, "One"),
+        ];
+        pages[1].metadata.page_in_chapter = Some(7);
+        number_from(&mut pages, 0);
+
+        assert_eq!(numbers(&pages), [Some(1), Some(7)]);
+    }
+
+    #[test]
+    fn pages_without_a_chapter_arent_numbered() {
+        let mut pages = vec![
+            entry("1", None, Some("2"), ""),
+            entry("2", Some("1"), None, ""),
+        ];
+        number_from(&mut pages, 0);
+
+        assert_eq!(numbers(&pages), [None, None]);
+    }
+
+    #[test]
+    fn links_that_go_round_in_circles_dont_hang() {
+        // neither can be numbered, and each chapter change looks like a reason to carry on
+        let mut pages = vec![
+            entry("1", Some("0"), Some("2"), "One"),
+            entry("2", Some("0"), Some("1"), "Two"),
+        ];
+        number_from(&mut pages, 0);
+
+        assert_eq!(numbers(&pages), [None, None]);
+    }
+
+    #[test]
+    fn old_index_entries_keep_their_label_as_the_chapter() {
+        let json = r#"[
+            {"id": "20021104", "filename": "20021104.jpg", "previous": null, "next": "20021106", "label": "Volume 1"},
+            {"id": "20021106", "filename": "20021106.jpg", "metadata": {"chapter": "Volume 2", "page_in_chapter": 3}}
+        ]"#;
+        let pages: Vec<GggCacheData> = serde_json::from_str(json).unwrap();
+
+        assert_eq!(pages[0].chapter(), Some("Volume 1"));
+        assert_eq!(pages[0].next.as_deref(), Some("20021106"));
+        assert_eq!(pages[1].chapter(), Some("Volume 2"));
+        assert_eq!(pages[1].metadata.page_in_chapter, Some(3));
+        assert_eq!(pages[1].previous, None);
+    }
+
+    #[test]
+    fn pages_without_metadata_are_stored_without_it() {
+        let json = serde_json::to_string(&entry("1", None, None, "")).unwrap();
+
+        assert_eq!(
+            json,
+            r#"{"id":"1","filename":"1.png","previous":null,"next":null}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn images_saved_before_an_interrupted_sync_are_not_downloaded_again() {
+        let cache_dir = std::env::temp_dir()
+            .join(format!("ggg-unrecorded-{}", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        fs::create_dir_all(&cache_dir).unwrap();
+        image::RgbImage::new(1, 1)
+            .save(format!("{}/20021104.png", cache_dir))
+            .unwrap();
+        fs::write(format!("{}/20021106.png", cache_dir), b"\x89PNG\r\n\x1a\n").unwrap();
+
+        let page = |id: &str| ComicPage {
+            id: Some(id.to_string()),
+            current_url: format!("http://127.0.0.1:9/{}", id),
+            // nothing listens here, so fetching the image would fail
+            current_image: format!("http://127.0.0.1:9/{}.png", id),
+            previous: None,
+            next: None,
+            last: None,
+            metadata: PageMetadata::default(),
+        };
+        let saved = page("20021104").download(&cache_dir).await;
+        let truncated = page("20021106").download(&cache_dir).await;
+        fs::remove_dir_all(&cache_dir).unwrap();
+
+        let (data, fetched) = saved.unwrap();
+        assert_eq!(data.filename, "20021104.png");
+        assert!(!fetched);
+        assert!(truncated.is_err());
+    }
+}
@@ -0,0 +1,194 @@
+use std::{collections::HashMap, sync::Arc};
+
+use tokio::{
+    sync::Semaphore,
+    task::{JoinError, JoinSet},
+};
+
+use crate::{
+    cache::{prune, Limits},
+    comic::Comic,
+    structs::{ComicPage, Config, GggCacheData},
+    Error,
+};
+
+/// Where a sync is up to, handed to the progress callback after every page.
+#[derive(Debug, Clone)]
+pub struct SyncProgress {
+    /// The page that was just visited.
+    pub id: String,
+    /// Pages that were already cached, and didn't need any requests.
+    pub already_cached: usize,
+    /// Pages whose image has been queued for download.
+    pub queued: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    pub already_cached: usize,
+    pub downloaded: usize,
+    /// Pages whose image couldn't be saved. Running the sync again will retry them.
+    pub failed: Vec<(String, Error)>,
+}
+
+/// How many downloaded pages to hold on to before writing them to the cache index. Writing it
+/// rewrites all of `pages.json`, so doing it after every page would get slow on a long archive.
+/// Stopping with Ctrl-C still writes whatever's held.
+const RECORD_EVERY: usize = 100;
+
+/// Mirrors the whole archive into `cache_dir`, starting from the first page.
+///
+/// Pages that are already cached are walked through the index without touching the network,
+/// so an interrupted sync picks up where it stopped. Finding the next page has to happen one at
+/// a time, but up to `jobs` images are downloaded at once.
+pub async fn sync(
+    comic: &dyn Comic,
+    config: &Config,
+    cache_dir: String,
+    jobs: usize,
+    mut progress: impl FnMut(&SyncProgress),
+) -> Result<SyncReport, Error> {
+    let index: HashMap<String, GggCacheData> = GggCacheData::read(cache_dir.clone())
+        .into_iter()
+        .map(|p| (p.id.clone(), p))
+        .collect();
+    let semaphore = Arc::new(Semaphore::new(jobs.max(1)));
+    let mut downloads = JoinSet::new();
+    let mut report = SyncReport::default();
+    let mut queued = 0;
+
+    // downloaded pages that haven't been written to the index yet
+    let mut unrecorded = vec![];
+    let mut stopped = None;
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+
+    let mut current = Some(comic.first_page().to_string());
+    while let Some(id) = current {
+        let known = index
+            .get(&id)
+            .filter(|entry| entry.is_cached(&cache_dir))
+            .and_then(|entry| entry.next.clone());
+
+        current = match known {
+            Some(next) => {
+                report.already_cached += 1;
+                Some(next)
+            }
+            // either it isn't cached, or it was the newest page last time, so ask the site
+            None => {
+                let page = tokio::select! {
+                    page = ComicPage::new(comic, &id) => page,
+                    _ = &mut ctrl_c => Err(Error::Interrupted),
+                };
+                let page = match page {
+                    Ok(page) => page,
+                    Err(why) => {
+                        stopped = Some(why);
+                        break;
+                    }
+                };
+                let next = page.next.clone();
+
+                let permit = tokio::select! {
+                    permit = semaphore.clone().acquire_owned() => {
+                        permit.expect("the semaphore is never closed")
+                    }
+                    _ = &mut ctrl_c => {
+                        stopped = Some(Error::Interrupted);
+                        break;
+                    }
+                };
+                let cache_dir = cache_dir.clone();
+                let page_id = id.clone();
+                downloads.spawn(async move {
+                    let downloaded = page.download(&cache_dir).await;
+                    drop(permit);
+                    (page_id, downloaded)
+                });
+                queued += 1;
+
+                next
+            }
+        };
+
+        while let Some(finished) = downloads.try_join_next() {
+            downloaded(finished, &mut report, &mut unrecorded);
+        }
+        if unrecorded.len() >= RECORD_EVERY {
+            if let Err(why) = GggCacheData::record_all(&cache_dir, &unrecorded) {
+                stopped = Some(why);
+                break;
+            }
+            unrecorded.clear();
+        }
+
+        progress(&SyncProgress {
+            id,
+            already_cached: report.already_cached,
+            queued,
+        });
+    }
+
+    // whatever's already downloaded is worth keeping, even if the sync stopped early. After a
+    // Ctrl-C, the downloads still going are dropped rather than waited for.
+    let mut interrupted = matches!(stopped, Some(Error::Interrupted));
+    if interrupted {
+        downloads.abort_all();
+    }
+    loop {
+        let finished = if interrupted {
+            downloads.join_next().await
+        } else {
+            tokio::select! {
+                finished = downloads.join_next() => finished,
+                _ = &mut ctrl_c => {
+                    interrupted = true;
+                    stopped.get_or_insert(Error::Interrupted);
+                    downloads.abort_all();
+                    continue;
+                }
+            }
+        };
+        match finished {
+            Some(finished) => downloaded(finished, &mut report, &mut unrecorded),
+            None => break,
+        }
+    }
+    GggCacheData::record_all(&cache_dir, &unrecorded)?;
+    if let Some(why) = stopped {
+        return Err(why);
+    }
+
+    // pruning after every download would fight the sync, so it happens once at the end
+    if let Err(why) = prune(comic, config, Limits::from_config(config), &cache_dir, &[]) {
+        eprintln!("Couldn't prune the cache: {}", why);
+    }
+
+    Ok(report)
+}
+
+/// What a download task hands back: the page's id, and what `ComicPage::download` made of it.
+type Finished = Result<(String, Result<(GggCacheData, bool), Error>), JoinError>;
+
+/// Tallies a finished download, keeping the page to be recorded if it worked. Pages whose image
+/// turned out to be on disk already (they were only fetched for their links) count as cached.
+fn downloaded(finished: Finished, report: &mut SyncReport, unrecorded: &mut Vec<GggCacheData>) {
+    match finished {
+        Ok((_, Ok((data, fetched)))) => {
+            if fetched {
+                report.downloaded += 1;
+            } else {
+                report.already_cached += 1;
+            }
+            unrecorded.push(data);
+        }
+        Ok((id, Err(why))) => report.failed.push((id, why)),
+        // stopped by a Ctrl-C; the next sync will download it
+        Err(why) if why.is_cancelled() => {}
+        Err(why) => report.failed.push((
+            "?".to_string(),
+            Error::Cache(format!("A download task died: {}", why)),
+        )),
+    }
+}
@@ -1,14 +1,64 @@
-use iced::widget::{self, column, container, image, row, text};
-use iced::{Alignment, Application, Command, Element, Length, Theme};
+use chrono::{DateTime, Utc};
+use iced::keyboard::{self, KeyCode, Modifiers};
+use iced::widget::{self, column, container, image, row, scrollable, text, text_input};
+use iced::{
+    event, subscription, window, Alignment, Application, Command, Element, Event, Length,
+    Subscription, Theme,
+};
+use std::sync::Arc;
 
-use crate::structs::{ConfigFile, GirlGeniusPage};
-use crate::{next, parse_gg_string_for_date, previous};
+use crate::comic::{Comic, PageMetadata};
+use crate::history::History;
+use crate::structs::{Bookmark, ConfigFile, Keybindings};
+use crate::{
+    bounds, compare_ids, goto, next, or_from_cache, page, parse_date_id, previous, pruned,
+    ArchiveBounds, Prefetcher,
+};
 
 #[derive(Debug)]
-pub enum GggUi {
+pub struct GggUi {
+    comic: Arc<dyn Comic>,
+    state: State,
+    /// The last page that was shown successfully, so an error doesn't lose your place.
+    last_good: Option<UiPage>,
+    /// Whatever started the current (or most recent) load, so it can be retried.
+    last_action: Message,
+    /// Downloads the pages after the current one in the background.
+    prefetcher: Prefetcher,
+    /// What's been typed into the "go to date" box.
+    goto_input: String,
+    /// Why the last thing typed into the "go to date" box wasn't a date.
+    goto_error: Option<String>,
+    /// Where the archive starts and ends, once we know.
+    bounds: Option<ArchiveBounds>,
+    keys: Keybindings,
+    fullscreen: bool,
+    /// This comic's bookmarks, as of the last time the config was read.
+    bookmarks: Vec<Bookmark>,
+    /// Where the reading position is, as of the last time the config was read.
+    reading_position: String,
+    /// The bookmark navigation is moving, if it isn't the reading position.
+    active_bookmark: Option<String>,
+    /// What's been typed into the "new bookmark" box.
+    bookmark_input: String,
+    /// The bookmark being renamed, and its new name so far.
+    renaming: Option<(String, String)>,
+    /// Why the last bookmark change didn't work.
+    bookmark_error: Option<String>,
+    history: History,
+}
+
+#[derive(Debug)]
+enum State {
     Loading,
-    Loaded { image: UiPage },
-    Errored,
+    Loaded {
+        image: UiPage,
+        /// Something worth telling the reader that isn't bad enough for the error screen.
+        notice: Option<String>,
+    },
+    Errored {
+        error: Error,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -17,102 +67,631 @@ pub enum Message {
     Prev,
     Init,
     Loaded(Result<UiPage, Error>),
+    Retry,
+    OpenCached,
+    LastGood,
+    GotoInput(String),
+    GotoSubmit,
+    /// Jump to the page for a `YYYYMMDD` date, or the nearest one.
+    Goto(String),
+    First,
+    Latest,
+    Bounds(Result<ArchiveBounds, Error>),
+    /// A key was pressed somewhere that didn't already use it.
+    Key(KeyCode, Modifiers),
+    FocusGoto,
+    ToggleFullscreen,
+    /// Return to where we were before the last jump.
+    Back,
+    /// Undo a `Back`.
+    Forward,
+    BookmarkInput(String),
+    /// Bookmark the current page, under the name in the "new bookmark" box.
+    AddBookmark,
+    /// Read from a bookmark, or the reading position for `None`.
+    OpenBookmark(Option<String>),
+    StartRename(String),
+    RenameInput(String),
+    SubmitRename,
+    CancelRename,
+    DeleteBookmark(String),
+}
+
+impl GggUi {
+    /// What the key is bound to, if anything.
+    fn bound_to(&self, key: KeyCode, modifiers: Modifiers) -> Option<Message> {
+        let bindings = [
+            (&self.keys.prev, Message::Prev),
+            (&self.keys.next, Message::Next),
+            (&self.keys.first, Message::First),
+            (&self.keys.latest, Message::Latest),
+            (&self.keys.goto, Message::FocusGoto),
+            (&self.keys.fullscreen, Message::ToggleFullscreen),
+            (&self.keys.back, Message::Back),
+            (&self.keys.forward, Message::Forward),
+        ];
+
+        let message = bindings
+            .into_iter()
+            .find(|(keys, _)| keys.iter().any(|k| key_matches(k, key, modifiers)))
+            .map(|(_, message)| message)?;
+
+        // same as the buttons: there's no going past either end
+        if let State::Loaded { image, .. } = &self.state {
+            let (at_first, at_latest) = self.at_ends(image);
+            match message {
+                Message::First | Message::Prev if at_first => return None,
+                Message::Next | Message::Latest if at_latest => return None,
+                _ => {}
+            }
+        }
+
+        Some(message)
+    }
+
+    /// Whether `image` is the first page, and whether it's the newest. Without the archive's
+    /// bounds, the newest page can't be told apart, so it never is.
+    fn at_ends(&self, image: &UiPage) -> (bool, bool) {
+        match &self.bounds {
+            Some(bounds) => (
+                compare_ids(&image.id, &bounds.first).is_le(),
+                compare_ids(&image.id, &bounds.latest).is_ge(),
+            ),
+            None => (image.id == self.comic.first_page(), false),
+        }
+    }
+
+    /// Starts caching the pages after `image` in the background.
+    fn prefetch(&self, image: &UiPage) {
+        self.prefetcher.restart(
+            self.comic.clone(),
+            GggUi::config_file().read(),
+            image.id.clone(),
+            std::env::var("ggg_cache_path").unwrap(),
+            image.offline,
+        );
+    }
+
+    fn config_file() -> ConfigFile {
+        ConfigFile {
+            path: std::env::var("ggg_config_path").unwrap(),
+        }
+    }
+
+    /// Rereads the bookmarks (and reading position) from the config, since navigating moves
+    /// them.
+    fn refresh_bookmarks(&mut self) {
+        let config = GggUi::config_file().read();
+        self.reading_position = config.reading_position(self.comic.as_ref());
+        self.bookmarks = config
+            .bookmarks_for(self.comic.as_ref())
+            .into_iter()
+            .cloned()
+            .collect();
+        self.active_bookmark = config
+            .active_bookmark(self.comic.as_ref())
+            .map(|b| b.name.clone());
+    }
+
+    /// Moves the history along now that `image` is showing. What moved us there is in
+    /// `last_action`.
+    fn record_history(&mut self, image: &UiPage) {
+        if let Some(from) = self.last_good.as_ref().map(|p| p.id.clone()) {
+            if from != image.id {
+                match self.last_action {
+                    Message::Back => self.history.went_back(from),
+                    Message::Forward => self.history.went_forward(from),
+                    // reading page by page isn't worth going back through
+                    Message::Next | Message::Prev => {}
+                    _ => self.history.jumped(from),
+                }
+            }
+        }
+        self.history.viewed(&image.id);
+
+        let config_path = std::env::var("ggg_config_path").unwrap();
+        if let Err(why) = self.history.save(&config_path, self.comic.as_ref()) {
+            eprintln!("Couldn't save the history: {}", why);
+        }
+    }
+
+    /// Shows whether a bookmark change worked, and the bookmarks as they are now.
+    fn bookmarks_changed(&mut self, changed: Result<(), crate::Error>) {
+        self.bookmark_error = changed.err().map(|why| why.to_string());
+        self.refresh_bookmarks();
+    }
+
+    fn sidebar(&self) -> Element<'_, Message> {
+        let entry = |label: String, bookmark: Option<&String>, page: &str| {
+            let active = self.active_bookmark.as_ref() == bookmark;
+            let mut open = widget::button(text(format!(
+                "{}{}\n{}",
+                if active { "▶ " } else { "" },
+                label,
+                page
+            )))
+            .padding(10)
+            .width(Length::Fill);
+            // jumping mid-load would be lost when the load finishes
+            if !matches!(self.state, State::Loading) {
+                open = open.on_press(Message::OpenBookmark(bookmark.cloned()));
+            }
+            open
+        };
+
+        let mut list = column![
+            text("Bookmarks").size(24),
+            entry("Reading position".to_string(), None, &self.reading_position),
+        ]
+        .spacing(10);
+
+        for bookmark in &self.bookmarks {
+            list = list.push(match &self.renaming {
+                Some((name, new_name)) if name == &bookmark.name => column![
+                    text_input("New name", new_name, Message::RenameInput)
+                        .on_submit(Message::SubmitRename)
+                        .padding(10),
+                    row![
+                        button("Rename").on_press(Message::SubmitRename),
+                        button("Cancel").on_press(Message::CancelRename),
+                    ]
+                    .spacing(10),
+                ]
+                .spacing(5),
+                _ => column![
+                    entry(bookmark.name.clone(), Some(&bookmark.name), &bookmark.page),
+                    row![
+                        button("Rename").on_press(Message::StartRename(bookmark.name.clone())),
+                        button("Delete").on_press(Message::DeleteBookmark(bookmark.name.clone())),
+                    ]
+                    .spacing(10),
+                ]
+                .spacing(5),
+            });
+        }
+
+        let mut add = button("Bookmark this page");
+        if let State::Loaded { .. } = self.state {
+            add = add.on_press(Message::AddBookmark);
+        }
+        list = list.push(
+            text_input(
+                "Bookmark name",
+                &self.bookmark_input,
+                Message::BookmarkInput,
+            )
+            .on_submit(Message::AddBookmark)
+            .padding(10),
+        );
+        list = list.push(add);
+        if let Some(why) = &self.bookmark_error {
+            list = list.push(text(why));
+        }
+
+        list = list.push(text("Recently viewed").size(24));
+        let current = self.last_good.as_ref().map(|p| &p.id);
+        for id in &self.history.recent {
+            let mut recent = button(id).width(Length::Fill);
+            if current != Some(id) && !matches!(self.state, State::Loading) {
+                recent = recent.on_press(Message::Goto(id.clone()));
+            }
+            list = list.push(recent);
+        }
+
+        scrollable(list.padding(10)).height(Length::Fill).into()
+    }
+
+    fn load(&mut self, action: Message) -> Command<Message> {
+        if let State::Loading = self.state {
+            return Command::none();
+        }
+
+        self.start(action)
+    }
+
+    /// Like `load`, but doesn't check whether something's already loading.
+    fn start(&mut self, action: Message) -> Command<Message> {
+        let comic = self.comic.clone();
+        let nav = match action {
+            Message::Next => Nav::Next,
+            Message::Prev => Nav::Prev,
+            Message::Init => Nav::Current,
+            Message::OpenCached => {
+                return self.started(
+                    action,
+                    Command::perform(UiPage::cached(comic), Message::Loaded),
+                )
+            }
+            Message::Goto(ref id) => Nav::Goto(id.clone()),
+            Message::Back => match self.history.back_to() {
+                Some(id) => Nav::Goto(id.clone()),
+                None => return Command::none(),
+            },
+            Message::Forward => match self.history.forward_to() {
+                Some(id) => Nav::Goto(id.clone()),
+                None => return Command::none(),
+            },
+            Message::First => Nav::Goto(comic.first_page().to_string()),
+            Message::Latest => Nav::Latest,
+            _ => return Command::none(),
+        };
+
+        self.started(
+            action,
+            Command::perform(UiPage::fetch(comic, nav), Message::Loaded),
+        )
+    }
+
+    fn started(&mut self, action: Message, command: Command<Message>) -> Command<Message> {
+        self.state = State::Loading;
+        self.last_action = action;
+        command
+    }
 }
 
 impl Application for GggUi {
     type Message = Message;
     type Theme = Theme;
     type Executor = iced::executor::Default;
-    type Flags = ();
+    type Flags = Arc<dyn Comic>;
 
-    fn new(_flags: ()) -> (GggUi, Command<Message>) {
-        (
-            GggUi::Loading,
-            Command::perform(UiPage::init(), Message::Loaded),
-        )
+    fn new(comic: Arc<dyn Comic>) -> (GggUi, Command<Message>) {
+        let action = match std::env::var("ggg_goto") {
+            Ok(id) => Message::Goto(id),
+            Err(_) => Message::Init,
+        };
+        let mut ui = GggUi {
+            comic: comic.clone(),
+            state: State::Loading,
+            last_good: None,
+            last_action: Message::Init,
+            prefetcher: Prefetcher::default(),
+            goto_input: String::new(),
+            goto_error: None,
+            bounds: None,
+            keys: ConfigFile {
+                path: std::env::var("ggg_config_path").unwrap(),
+            }
+            .read()
+            .keys,
+            fullscreen: false,
+            bookmarks: vec![],
+            reading_position: String::new(),
+            active_bookmark: None,
+            bookmark_input: String::new(),
+            renaming: None,
+            bookmark_error: None,
+            history: History::load(&std::env::var("ggg_config_path").unwrap(), comic.as_ref()),
+        };
+        ui.refresh_bookmarks();
+        let command = Command::batch([
+            ui.start(action),
+            Command::perform(UiPage::bounds(comic), Message::Bounds),
+        ]);
+
+        (ui, command)
     }
 
     fn title(&self) -> String {
-        let subtitle = match self {
-            GggUi::Loading => "Loading...",
-            GggUi::Loaded { image, .. } => &image.date,
-            GggUi::Errored => "Error",
+        let subtitle = match &self.state {
+            State::Loading => "Loading...".to_string(),
+            State::Loaded { image, .. } => image.heading(),
+            State::Errored { .. } => "Error".to_string(),
         };
-        format!("GggUi - {}", subtitle)
+        format!("{} — {}", self.comic.title(), subtitle)
     }
 
     fn update(&mut self, message: Message) -> Command<Message> {
         match message {
             Message::Loaded(Ok(image)) => {
-                *self = GggUi::Loaded { image };
+                // a new page might have come out since we last checked
+                if let Some(bounds) = &mut self.bounds {
+                    if compare_ids(&image.id, &bounds.latest).is_gt() {
+                        bounds.latest = image.id.clone();
+                    }
+                }
+                self.prefetch(&image);
+                self.refresh_bookmarks();
+                self.record_history(&image);
+                self.last_good = Some(image.clone());
+                self.state = State::Loaded {
+                    image,
+                    notice: None,
+                };
 
                 Command::none()
             }
-            Message::Loaded(Err(_err)) => {
-                *self = GggUi::Errored;
+            // ran off the end of the cache while offline, so stay where we were
+            Message::Loaded(Err(Error::NotCached(what))) if self.last_good.is_some() => {
+                self.state = State::Loaded {
+                    image: self.last_good.clone().unwrap(),
+                    notice: Some(format!(
+                        "{} isn't cached, so this is as far as you can go offline.",
+                        what
+                    )),
+                };
                 Command::none()
             }
-            Message::Next => match self {
-                GggUi::Loading => Command::none(),
-                _ => {
-                    *self = GggUi::Loading;
-                    Command::perform(UiPage::next(), Message::Loaded)
-                }
+            Message::Loaded(Err(error)) => {
+                self.state = State::Errored { error };
+                Command::none()
+            }
+            Message::Retry => {
+                let action = self.last_action.clone();
+                self.load(action)
+            }
+            Message::Key(key, modifiers) => match self.bound_to(key, modifiers) {
+                Some(message) => self.update(message),
+                None => Command::none(),
             },
-            Message::Prev => match self {
-                GggUi::Loading => Command::none(),
-                _ => {
-                    *self = GggUi::Loading;
-                    Command::perform(UiPage::prev(), Message::Loaded)
+            Message::FocusGoto => text_input::focus(goto_input_id()),
+            Message::ToggleFullscreen => {
+                self.fullscreen = !self.fullscreen;
+                window::set_mode(if self.fullscreen {
+                    window::Mode::Fullscreen
+                } else {
+                    window::Mode::Windowed
+                })
+            }
+            Message::Bounds(bounds) => {
+                self.bounds = bounds.ok();
+                Command::none()
+            }
+            Message::GotoInput(input) => {
+                self.goto_input = input;
+                self.goto_error = None;
+                Command::none()
+            }
+            Message::GotoSubmit => match self.comic.parse_id(&self.goto_input) {
+                Ok(id) => {
+                    self.goto_error = None;
+                    self.load(Message::Goto(id))
                 }
-            },
-            Message::Init => match self {
-                GggUi::Loading => Command::none(),
-                _ => {
-                    *self = GggUi::Loading;
-                    Command::perform(UiPage::init(), Message::Loaded)
+                Err(why) => {
+                    self.goto_error = Some(why.to_string());
+                    Command::none()
                 }
             },
+            Message::BookmarkInput(input) => {
+                self.bookmark_input = input;
+                Command::none()
+            }
+            Message::AddBookmark => {
+                if let State::Loaded { image, .. } = &self.state {
+                    let added = GggUi::config_file().add_bookmark(
+                        self.comic.as_ref(),
+                        &self.bookmark_input,
+                        image.id.clone(),
+                    );
+                    if added.is_ok() {
+                        self.bookmark_input.clear();
+                    }
+                    self.bookmarks_changed(added);
+                }
+                Command::none()
+            }
+            Message::OpenBookmark(name) => {
+                if let State::Loading = self.state {
+                    return Command::none();
+                }
+                let opened =
+                    GggUi::config_file().activate_bookmark(self.comic.as_ref(), name.as_deref());
+                let command = match opened {
+                    Ok(_) => self.load(Message::Init),
+                    Err(_) => Command::none(),
+                };
+                self.bookmarks_changed(opened.map(|_| ()));
+                command
+            }
+            Message::StartRename(name) => {
+                self.renaming = Some((name.clone(), name));
+                Command::none()
+            }
+            Message::RenameInput(input) => {
+                if let Some((_, new_name)) = &mut self.renaming {
+                    *new_name = input;
+                }
+                Command::none()
+            }
+            Message::SubmitRename => {
+                if let Some((name, new_name)) = self.renaming.clone() {
+                    let renamed =
+                        GggUi::config_file().rename_bookmark(self.comic.as_ref(), &name, &new_name);
+                    if renamed.is_ok() {
+                        self.renaming = None;
+                    }
+                    self.bookmarks_changed(renamed);
+                }
+                Command::none()
+            }
+            Message::CancelRename => {
+                self.renaming = None;
+                self.bookmark_error = None;
+                Command::none()
+            }
+            Message::DeleteBookmark(name) => {
+                let removed = GggUi::config_file().remove_bookmark(self.comic.as_ref(), &name);
+                self.bookmarks_changed(removed);
+                Command::none()
+            }
+            Message::LastGood => {
+                if let Some(image) = self.last_good.clone() {
+                    GggUi::config_file().update_position(self.comic.as_ref(), image.id.clone());
+                    self.refresh_bookmarks();
+                    self.prefetch(&image);
+                    self.state = State::Loaded {
+                        image,
+                        notice: None,
+                    };
+                }
+                Command::none()
+            }
+            action => self.load(action),
         }
     }
 
-    fn view(&self) -> Element<Message> {
-        let content = match self {
-            GggUi::Loading => column![text("Loading page...").size(40),].width(Length::Shrink),
-            GggUi::Loaded { image } => column![
-                image.view(),
-                row![
-                    button("Prev").on_press(Message::Prev),
-                    button("Next").on_press(Message::Next),
+    fn subscription(&self) -> Subscription<Message> {
+        subscription::events_with(|event, status| match (event, status) {
+            (
+                Event::Keyboard(keyboard::Event::KeyPressed {
+                    key_code,
+                    modifiers,
+                }),
+                event::Status::Ignored,
+            ) => Some(Message::Key(key_code, modifiers)),
+            _ => None,
+        })
+    }
+
+    fn view(&self) -> Element<'_, Message> {
+        let content = match &self.state {
+            State::Loading => column![text("Loading page...").size(40),].width(Length::Shrink),
+            State::Loaded { image, notice } => {
+                let (at_first, at_latest) = self.at_ends(image);
+                let (mut first, mut prev) = (button("First"), button("Prev"));
+                let (mut next, mut latest) = (button("Next"), button("Latest"));
+                let (mut back, mut forward) = (button("Back"), button("Forward"));
+                if self.history.back_to().is_some() {
+                    back = back.on_press(Message::Back);
+                }
+                if self.history.forward_to().is_some() {
+                    forward = forward.on_press(Message::Forward);
+                }
+                if !at_first {
+                    first = first.on_press(Message::First);
+                    prev = prev.on_press(Message::Prev);
+                }
+                if !at_latest {
+                    next = next.on_press(Message::Next);
+                    latest = latest.on_press(Message::Latest);
+                }
+
+                let mut content = column![
+                    image.view(),
+                    text(image.status(self.bounds.as_ref())).size(16),
+                    row![
+                        back,
+                        forward,
+                        first,
+                        prev,
+                        next,
+                        latest,
+                        text_input("YYYYMMDD", &self.goto_input, Message::GotoInput)
+                            .id(goto_input_id())
+                            .on_submit(Message::GotoSubmit)
+                            .padding(10)
+                            .width(Length::Units(120)),
+                        button("Go to date").on_press(Message::GotoSubmit),
+                    ]
+                    .spacing(10)
+                    .align_items(Alignment::Center)
+                ]
+                .spacing(20)
+                .align_items(Alignment::Center);
+
+                if let Some(why) = &self.goto_error {
+                    content = content.push(text(why));
+                }
+                if let Some(notice) = notice {
+                    content = content.push(text(notice));
+                }
+
+                content
+            }
+            State::Errored { error } => {
+                let mut last_good = button("Go to last known good page");
+                if self.last_good.is_some() {
+                    last_good = last_good.on_press(Message::LastGood);
+                }
+
+                column![
+                    text(error.title()).size(40),
+                    text(error.detail()).size(20),
+                    row![
+                        button("Retry").on_press(Message::Retry),
+                        button("Open cached copy").on_press(Message::OpenCached),
+                        last_good,
+                    ]
+                    .spacing(10)
+                    .align_items(Alignment::Center)
                 ]
+                .spacing(20)
                 .align_items(Alignment::Center)
-            ]
-            .spacing(20)
-            .align_items(Alignment::Center),
-            GggUi::Errored => column![
-                text("something broke").size(40),
-                button("try to load the current page").on_press(Message::Init)
-            ]
-            .spacing(20)
-            .align_items(Alignment::Center),
+            }
         };
 
-        container(content)
+        let content = container(content)
             .width(Length::Fill)
             .height(Length::Fill)
-            .align_x(iced::alignment::Horizontal::Center)
-            .into()
+            .align_x(iced::alignment::Horizontal::Center);
+
+        if self.fullscreen {
+            return content.into();
+        }
+        row![container(self.sidebar()).width(Length::Units(220)), content].into()
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct UiPage {
     image: image::Handle,
-    date: String,
+    id: String,
+    /// When the page went up, for comics whose ids are dates.
+    date: Option<DateTime<Utc>>,
+    /// The chapter the page is part of and so on, if the site told us.
+    metadata: PageMetadata,
+    /// Whether the image was already cached, rather than just downloaded.
+    from_cache: bool,
+    /// Whether this page came from the cache because we're offline.
+    offline: bool,
 }
 
 impl UiPage {
-    fn view(&self) -> Element<Message> {
+    /// Something like `Wed 2009-03-18 (Volume 9, page 12)`, or `#123` for comics without
+    /// dates, followed by the page's title if it has one.
+    fn heading(&self) -> String {
+        let date = match self.date {
+            Some(date) => date.format("%a %Y-%m-%d").to_string(),
+            None => format!("#{}", self.id),
+        };
+        let mut heading = match (&self.metadata.chapter, self.metadata.page_in_chapter) {
+            (Some(chapter), Some(page)) => format!("{} ({}, page {})", date, chapter, page),
+            (Some(chapter), None) => format!("{} ({})", date, chapter),
+            (None, _) => date,
+        };
+        if let Some(title) = &self.metadata.title {
+            heading.push_str(&format!(": {}", title));
+        }
+        heading
+    }
+
+    /// The date, where the image came from, and how far through the archive it is.
+    fn status(&self, bounds: Option<&ArchiveBounds>) -> String {
+        let source = match (self.from_cache, self.offline) {
+            (_, true) => "from the cache (offline)",
+            (true, false) => "from the cache",
+            (false, false) => "downloaded",
+        };
+        let mut status = format!("{} · {}", self.heading(), source);
+
+        let range = bounds.and_then(|b| {
+            let first = position(&b.first)?;
+            let latest = position(&b.latest)?;
+            Some((first, latest, position(&self.id)?))
+        });
+        if let Some((first, latest, current)) = range {
+            let total = (latest - first).max(1.0);
+            let done = current - first;
+            status.push_str(&format!(
+                " · {:.0}% through the archive",
+                (done / total * 100.0).clamp(0.0, 100.0)
+            ));
+        }
+
+        status
+    }
+
+    fn view(&self) -> Element<'_, Message> {
         row![image::viewer(self.image.clone())]
             .spacing(20)
             .align_items(Alignment::Center)
@@ -121,80 +700,307 @@ impl UiPage {
             .into()
     }
 
-    async fn prev() -> Result<UiPage, Error> {
+    /// Shows the current page straight from the cache, without touching the network.
+    async fn cached(comic: Arc<dyn Comic>) -> Result<UiPage, Error> {
+        Ok(UiPage::fetch_with(comic.as_ref(), Nav::Current, true).await?)
+    }
+
+    async fn bounds(comic: Arc<dyn Comic>) -> Result<ArchiveBounds, Error> {
+        let cache_dir = std::env::var("ggg_cache_path").unwrap();
+        let offline = std::env::var("ggg_offline").is_ok();
+
+        Ok(or_from_cache(offline, |offline| {
+            bounds(comic.as_ref(), &cache_dir, offline)
+        })
+        .await?)
+    }
+
+    /// Navigates, falling back to the cache if the site can't be reached.
+    async fn fetch(comic: Arc<dyn Comic>, nav: Nav) -> Result<UiPage, Error> {
+        let offline = std::env::var("ggg_offline").is_ok();
+
+        Ok(or_from_cache(offline, |offline| {
+            UiPage::fetch_with(comic.as_ref(), nav.clone(), offline)
+        })
+        .await?)
+    }
+
+    async fn fetch_with(
+        comic: &dyn Comic,
+        nav: Nav,
+        offline: bool,
+    ) -> Result<UiPage, crate::Error> {
         let conf = ConfigFile {
             path: std::env::var("ggg_config_path").unwrap(),
         };
+        let cache_dir = std::env::var("ggg_cache_path").unwrap();
 
-        if conf.read().latest_page == String::from("20021104") {
-            Ok(UiPage {
-                image: image::Handle::from_path(format!(
-                    "{}/20021104.jpg",
-                    std::env::var("ggg_cache_path").unwrap()
-                )),
-                date: "2022 11 04".to_string(),
-            })
-        } else {
-            let prev = previous(conf.clone(), &std::env::var("ggg_cache_path").unwrap()).await;
-
-            let image = match prev {
-                Some((_, path)) => image::Handle::from_path(path),
-                None => image::Handle::from_path(
-                    GirlGeniusPage::new(parse_gg_string_for_date(conf.clone().read().latest_page))
-                        .await
-                        .save(&std::env::var("ggg_cache_path").unwrap())
-                        .await,
-                ),
-            };
-
-            Ok(UiPage {
-                image,
-                date: "fuck you this is a banana not a dried plum".to_string(),
-            })
-        }
-    }
-    async fn next() -> Result<UiPage, Error> {
-        let image = image::Handle::from_path(
-            next(
-                ConfigFile {
-                    path: std::env::var("ggg_config_path").unwrap(),
-                },
-                &std::env::var("ggg_cache_path").unwrap(),
-            )
-            .await
-            .unwrap()
-            .1,
-        );
+        let moved = match nav {
+            Nav::Next => next(comic, conf.clone(), &cache_dir, offline).await?,
+            Nav::Prev => previous(comic, conf.clone(), &cache_dir, offline).await?,
+            Nav::Goto(id) => Some(goto(comic, conf.clone(), &cache_dir, id, offline).await?),
+            Nav::Latest => {
+                let latest = bounds(comic, &cache_dir, offline).await?.latest;
+                Some(goto(comic, conf.clone(), &cache_dir, latest, offline).await?)
+            }
+            Nav::Current => None,
+        };
+        // if we didn't move (first page, newest page, or we weren't trying to), show where we are
+        let page = match moved {
+            Some(moved) => moved,
+            None => {
+                let config = conf.read();
+                let page = page(comic, config.position(comic), &cache_dir, offline).await?;
+                pruned(comic, &config, &cache_dir, &page);
+                page
+            }
+        };
 
         Ok(UiPage {
-            image,
-            date: "fuck you this is a cliff not a dried plum".to_string(),
+            image: image::Handle::from_path(page.path),
+            date: parse_date_id(&page.data.id).ok(),
+            id: page.data.id,
+            metadata: page.data.metadata,
+            from_cache: page.from_cache,
+            offline,
         })
     }
-    async fn init() -> Result<UiPage, Error> {
-        let image = image::Handle::from_path(
-            GirlGeniusPage::new(parse_gg_string_for_date(
-                ConfigFile {
-                    path: std::env::var("ggg_config_path").unwrap(),
-                }
-                .read()
-                .latest_page,
-            ))
-            .await
-            .save(&std::env::var("ggg_cache_path").unwrap())
-            .await,
-        );
+}
 
-        Ok(UiPage {
-            image,
-            date: "fuck you this is a lights not a dried plum".to_string(),
-        })
-    }
+#[derive(Debug, Clone)]
+enum Nav {
+    Next,
+    Prev,
+    Goto(String),
+    Latest,
+    Current,
 }
 
 #[derive(Debug, Clone)]
-pub enum Error {}
+pub enum Error {
+    /// Couldn't reach the site at all.
+    NetworkDown(String),
+    /// The site doesn't have that page.
+    PageNotFound(String),
+    /// The page loaded, but a selector we need matched nothing.
+    ScrapeFailed {
+        url: String,
+        selector: String,
+    },
+    /// The cache directory couldn't be read from or written to.
+    CacheUnwritable(String),
+    /// There's no cached copy of the given page.
+    NotCached(String),
+    Other(String),
+}
+
+impl Error {
+    fn title(&self) -> &'static str {
+        match self {
+            Error::NetworkDown(_) => "Couldn't reach the comic's site",
+            Error::PageNotFound(_) => "That page doesn't exist",
+            Error::ScrapeFailed { .. } => "Couldn't find the comic on the page",
+            Error::CacheUnwritable(_) => "Couldn't use the cache directory",
+            Error::NotCached(_) => "That page isn't cached",
+            Error::Other(_) => "Something broke",
+        }
+    }
+
+    fn detail(&self) -> String {
+        match self {
+            Error::NetworkDown(why) => format!("Check your connection, then retry. ({})", why),
+            Error::PageNotFound(url) => format!("`{}` returned 404.", url),
+            Error::ScrapeFailed { url, selector } => format!(
+                "`{}` matched nothing on `{}`. The site's layout may have changed.",
+                selector, url
+            ),
+            Error::CacheUnwritable(why) => format!(
+                "Make sure the cache directory exists and is writable. ({})",
+                why
+            ),
+            Error::NotCached(id) => format!("{} hasn't been downloaded yet.", id),
+            Error::Other(why) => why.clone(),
+        }
+    }
+}
+
+impl From<crate::Error> for Error {
+    fn from(why: crate::Error) -> Self {
+        match why {
+            crate::Error::Network(_) => Error::NetworkDown(why.to_string()),
+            crate::Error::Status { url, status } if status == reqwest::StatusCode::NOT_FOUND => {
+                Error::PageNotFound(url)
+            }
+            crate::Error::Scrape { url, selector } => Error::ScrapeFailed { url, selector },
+            crate::Error::NotCached(what) => Error::NotCached(what),
+            crate::Error::Io { .. } | crate::Error::Cache(_) => {
+                Error::CacheUnwritable(why.to_string())
+            }
+            _ => Error::Other(why.to_string()),
+        }
+    }
+}
+
+/// Where a page id sits in the archive, as a number: days since the epoch for dates, or the id
+/// itself for comics that number their pages.
+fn position(id: &str) -> Option<f64> {
+    match parse_date_id(id) {
+        Ok(date) => Some(date.timestamp() as f64 / 86400.0),
+        Err(_) => id.parse::<f64>().ok(),
+    }
+}
+
+fn goto_input_id() -> text_input::Id {
+    text_input::Id::new("goto")
+}
+
+/// Whether a binding like `Alt+Left` is the key that was pressed. The key's name is compared
+/// against the `KeyCode`'s, ignoring case, and the modifiers have to match exactly.
+fn key_matches(binding: &str, key: KeyCode, modifiers: Modifiers) -> bool {
+    let mut parts: Vec<&str> = binding.split('+').map(|p| p.trim()).collect();
+    let name = match parts.pop() {
+        Some(name) => name,
+        None => return false,
+    };
+    let wants = |modifier: &str| parts.iter().any(|p| p.eq_ignore_ascii_case(modifier));
+
+    name.eq_ignore_ascii_case(&format!("{:?}", key))
+        && wants("Alt") == modifiers.alt()
+        && (wants("Ctrl") || wants("Control")) == modifiers.control()
+        && wants("Shift") == modifiers.shift()
+        && (wants("Super") || wants("Logo")) == modifiers.logo()
+}
 
 fn button(text: &str) -> widget::Button<'_, Message> {
     widget::button(text).padding(10)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_match_by_name_ignoring_case() {
+        let none = Modifiers::empty();
+
+        assert!(key_matches("Left", KeyCode::Left, none));
+        assert!(key_matches("left", KeyCode::Left, none));
+        assert!(key_matches("PageDown", KeyCode::PageDown, none));
+        assert!(key_matches("h", KeyCode::H, none));
+        assert!(!key_matches("Right", KeyCode::Left, none));
+        assert!(!key_matches("", KeyCode::Left, none));
+    }
+
+    #[test]
+    fn modifiers_have_to_match_exactly() {
+        assert!(key_matches("Alt+Left", KeyCode::Left, Modifiers::ALT));
+        assert!(key_matches("alt + left", KeyCode::Left, Modifiers::ALT));
+        assert!(!key_matches("Alt+Left", KeyCode::Left, Modifiers::empty()));
+        assert!(!key_matches("Left", KeyCode::Left, Modifiers::ALT));
+        assert!(!key_matches(
+            "Alt+Left",
+            KeyCode::Left,
+            Modifiers::ALT | Modifiers::SHIFT
+        ));
+    }
+
+    #[test]
+    fn modifiers_have_other_names_too() {
+        assert!(key_matches("Ctrl+g", KeyCode::G, Modifiers::CTRL));
+        assert!(key_matches("Control+g", KeyCode::G, Modifiers::CTRL));
+        assert!(key_matches("Super+g", KeyCode::G, Modifiers::LOGO));
+        assert!(key_matches("Logo+g", KeyCode::G, Modifiers::LOGO));
+        assert!(key_matches(
+            "Ctrl+Shift+g",
+            KeyCode::G,
+            Modifiers::CTRL | Modifiers::SHIFT
+        ));
+    }
+
+    /// The app showing page `id`, within `bounds` if they're known.
+    fn showing(id: &str, bounds: Option<(&str, &str)>) -> GggUi {
+        GggUi {
+            comic: Arc::new(crate::girl_genius::GirlGenius),
+            state: State::Loaded {
+                image: UiPage {
+                    image: image::Handle::from_memory(vec![]),
+                    id: id.to_string(),
+                    date: None,
+                    metadata: PageMetadata::default(),
+                    from_cache: true,
+                    offline: false,
+                },
+                notice: None,
+            },
+            last_good: None,
+            last_action: Message::Init,
+            prefetcher: Prefetcher::default(),
+            goto_input: String::new(),
+            goto_error: None,
+            bounds: bounds.map(|(first, latest)| ArchiveBounds {
+                first: first.to_string(),
+                latest: latest.to_string(),
+            }),
+            keys: Keybindings::default(),
+            fullscreen: false,
+            bookmarks: vec![],
+            reading_position: id.to_string(),
+            active_bookmark: None,
+            bookmark_input: String::new(),
+            renaming: None,
+            bookmark_error: None,
+            history: History::default(),
+        }
+    }
+
+    fn pressed(ui: &GggUi, key: KeyCode) -> Option<Message> {
+        ui.bound_to(key, Modifiers::empty())
+    }
+
+    #[test]
+    fn keys_do_not_move_past_either_end() {
+        let first = showing("20021104", Some(("20021104", "20240101")));
+        assert!(pressed(&first, KeyCode::Left).is_none());
+        assert!(pressed(&first, KeyCode::Home).is_none());
+        assert!(matches!(
+            pressed(&first, KeyCode::Right),
+            Some(Message::Next)
+        ));
+        assert!(matches!(
+            pressed(&first, KeyCode::End),
+            Some(Message::Latest)
+        ));
+
+        let latest = showing("20240101", Some(("20021104", "20240101")));
+        assert!(pressed(&latest, KeyCode::Right).is_none());
+        assert!(pressed(&latest, KeyCode::End).is_none());
+        assert!(matches!(
+            pressed(&latest, KeyCode::Left),
+            Some(Message::Prev)
+        ));
+        assert!(matches!(
+            pressed(&latest, KeyCode::Home),
+            Some(Message::First)
+        ));
+    }
+
+    #[test]
+    fn without_bounds_only_the_first_page_is_an_end() {
+        let first = showing("20021104", None);
+        assert!(pressed(&first, KeyCode::Left).is_none());
+        assert!(matches!(
+            pressed(&first, KeyCode::End),
+            Some(Message::Latest)
+        ));
+
+        let later = showing("20100101", None);
+        assert!(matches!(
+            pressed(&later, KeyCode::Left),
+            Some(Message::Prev)
+        ));
+        assert!(matches!(
+            pressed(&later, KeyCode::Right),
+            Some(Message::Next)
+        ));
+    }
+}
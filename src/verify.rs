@@ -0,0 +1,224 @@
+use std::{collections::HashSet, fs, path::Path, sync::Arc};
+
+use tokio::{sync::Semaphore, task::JoinSet};
+
+use crate::{comic::Comic, page, structs::GggCacheData, Error};
+
+/// Files in a cache directory that aren't page images.
+const BOOKKEEPING: [&str; 5] = [
+    "pages.json",
+    "pages.json.corrupt",
+    "pages.json.tmp",
+    "pages.json.lock",
+    ".formats-checked",
+];
+
+/// What `verify` found wrong with a cache directory.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Images that decoded fine.
+    pub ok: usize,
+    /// Pages whose image is there but won't decode, and why.
+    pub corrupt: Vec<(GggCacheData, String)>,
+    /// Pages in the index whose image isn't on disk.
+    pub missing: Vec<GggCacheData>,
+    /// Good images named after a page that the index doesn't know about, like everything
+    /// cached before there was an index.
+    pub unindexed: Vec<GggCacheData>,
+    /// Junk: leftovers from interrupted downloads, and files that aren't images.
+    pub orphaned: Vec<String>,
+    /// Images that aren't named after a page, so we don't know what they are. They're left
+    /// alone.
+    pub unknown: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_clean(&self) -> bool {
+        self.corrupt.is_empty()
+            && self.missing.is_empty()
+            && self.unindexed.is_empty()
+            && self.orphaned.is_empty()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    pub downloaded: usize,
+    /// Unindexed images that were added to the index.
+    pub adopted: usize,
+    /// Orphaned files that were deleted.
+    pub removed: usize,
+    pub failed: Vec<(String, Error)>,
+}
+
+/// A file `verify` decodes.
+struct Check {
+    filename: String,
+    /// The page it's the image for, if it's in the index or named after one.
+    entry: Option<GggCacheData>,
+    indexed: bool,
+}
+
+/// Decodes every image in the cache directory, and checks the index and the directory agree.
+/// Up to `jobs` images are decoded at once; `progress` gets how many have been checked so far,
+/// out of how many there are.
+pub async fn verify(
+    comic: &dyn Comic,
+    cache_dir: String,
+    jobs: usize,
+    mut progress: impl FnMut(usize, usize),
+) -> Result<VerifyReport, Error> {
+    let index = GggCacheData::read(cache_dir.clone());
+    let mut report = VerifyReport::default();
+
+    let indexed = index
+        .iter()
+        .map(|p| p.filename.clone())
+        .collect::<HashSet<String>>();
+    let files = fs::read_dir(&cache_dir).map_err(|why| Error::io(cache_dir.clone(), why))?;
+    let unindexed = files
+        .filter_map(|f| f.ok())
+        .filter(|f| f.path().is_file())
+        .filter_map(|f| f.file_name().to_str().map(|s| s.to_string()))
+        .filter(|f| !BOOKKEEPING.contains(&&f[..]))
+        .filter(|f| !indexed.contains(f));
+
+    let mut checks = vec![];
+    for filename in unindexed {
+        // an interrupted download, whether or not it got as far as writing the whole image
+        if filename.starts_with('.') && filename.ends_with(".tmp") {
+            report.orphaned.push(filename);
+            continue;
+        }
+        checks.push(Check {
+            entry: GggCacheData::from_filename(comic, &filename),
+            filename,
+            indexed: false,
+        });
+    }
+
+    let (present, missing): (Vec<GggCacheData>, Vec<GggCacheData>) =
+        index.into_iter().partition(|p| p.is_cached(&cache_dir));
+    report.missing = missing;
+    checks.extend(present.into_iter().map(|entry| Check {
+        filename: entry.filename.clone(),
+        entry: Some(entry),
+        indexed: true,
+    }));
+
+    let semaphore = Arc::new(Semaphore::new(jobs.max(1)));
+    let mut decodes = JoinSet::new();
+    let total = checks.len();
+    for check in checks {
+        let permit = semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        let path = Path::new(&cache_dir).join(&check.filename);
+        decodes.spawn_blocking(move || {
+            let decoded = decode(&path);
+            drop(permit);
+            (check, decoded)
+        });
+    }
+
+    let mut checked = 0;
+    while let Some(finished) = decodes.join_next().await {
+        let (check, decoded) = match finished {
+            Ok(finished) => finished,
+            Err(why) => {
+                return Err(Error::Cache(format!("A verify task died: {}", why)));
+            }
+        };
+        match (check.entry, decoded) {
+            (Some(_), Ok(())) if check.indexed => report.ok += 1,
+            (Some(entry), Ok(())) => report.unindexed.push(entry),
+            // it's named after a page, so it can be downloaded again
+            (Some(entry), Err(why)) => report.corrupt.push((entry, why)),
+            (None, Ok(())) => report.unknown.push(check.filename),
+            (None, Err(_)) => report.orphaned.push(check.filename),
+        }
+        checked += 1;
+        progress(checked, total);
+    }
+    report
+        .corrupt
+        .sort_by(|a, b| crate::compare_ids(&a.0.id, &b.0.id));
+    report
+        .unindexed
+        .sort_by(|a, b| crate::compare_ids(&a.id, &b.id));
+    report.orphaned.sort();
+    report.unknown.sort();
+
+    Ok(report)
+}
+
+/// Whether the image at `path` decodes all the way through (truncated downloads don't).
+pub(crate) fn decode(path: &Path) -> Result<(), String> {
+    image::io::Reader::open(path)
+        .map_err(|why| why.to_string())?
+        .with_guessed_format()
+        .map_err(|why| why.to_string())?
+        .decode()
+        .map(|_| ())
+        .map_err(|why| why.to_string())
+}
+
+/// Fixes what `verify` found: corrupt and missing images are downloaded again, unindexed ones
+/// are added to the index (renamed, if their extension was wrong), and orphaned files are
+/// deleted.
+pub async fn repair(comic: &dyn Comic, cache_dir: String, report: &VerifyReport) -> RepairReport {
+    let mut repaired = RepairReport::default();
+
+    for (entry, _) in &report.corrupt {
+        let path = entry.path(&cache_dir);
+        if let Err(why) = fs::remove_file(&path) {
+            repaired
+                .failed
+                .push((entry.id.clone(), Error::io(path, why)));
+        }
+    }
+
+    let redownload = report
+        .corrupt
+        .iter()
+        .map(|(entry, _)| entry)
+        .chain(report.missing.iter());
+    for entry in redownload {
+        if entry.is_cached(&cache_dir) {
+            continue;
+        }
+        match page(comic, entry.id.clone(), &cache_dir, false).await {
+            Ok(_) => repaired.downloaded += 1,
+            Err(why) => repaired.failed.push((entry.id.clone(), why)),
+        }
+    }
+
+    let mut adopted = vec![];
+    for entry in &report.unindexed {
+        let mut entry = entry.clone();
+        match entry.match_extension(&cache_dir) {
+            Ok(Some(_)) => adopted.push(entry),
+            // it decoded, so this can only happen if it's been replaced since
+            Ok(None) => {}
+            Err(why) => repaired.failed.push((entry.id.clone(), why)),
+        }
+    }
+    match GggCacheData::record_all(&cache_dir, &adopted) {
+        Ok(()) => repaired.adopted += adopted.len(),
+        Err(why) => repaired.failed.push(("the cache index".to_string(), why)),
+    }
+
+    for orphan in &report.orphaned {
+        let path = Path::new(&cache_dir).join(orphan);
+        match fs::remove_file(&path) {
+            Ok(()) => repaired.removed += 1,
+            Err(why) => repaired
+                .failed
+                .push((orphan.clone(), Error::io(path.display().to_string(), why))),
+        }
+    }
+
+    repaired
+}